@@ -0,0 +1,159 @@
+//! # Analysis
+//! A pre-flight estimate of how long the exhaustive search takes, without running it.
+//!
+//! The size of the search tree is estimated by following random paths from the root
+//! (Knuth's estimator): the product of the numbers of options along a path estimates
+//! the number of nodes at that depth. The paths use the same options as the search,
+//! so they respect the cap on the number of shifts per person, rests, pins and pairings.
+//! Branch and bound is not taken into account, so the search usually visits far fewer nodes.
+
+use crate::data::*;
+use crate::scheduling::{evaluate, SchedulingOptions, SearchContext};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Instant;
+
+/// The number of random paths followed for the estimate
+const PROBES: usize = 1000;
+
+/// The size of the search space of a poll
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchSpace {
+    /// For each slot, the number of people available and the number of ways to fill it on its own
+    pub slots: Vec<(usize, f64)>,
+    /// The number of shifts the exhaustive search assigns a person at most, unless their limits allow more
+    pub max_occur: usize,
+    /// The number of complete schedules without any pruning
+    pub schedules: f64,
+    /// The estimated number of complete schedules the search evaluates without branch and bound
+    pub leaves: f64,
+    /// The estimated number of partial and complete schedules the search visits without branch and bound
+    pub nodes: f64,
+    /// The estimated time in seconds of the search without branch and bound, using all threads
+    pub seconds: f64,
+}
+
+impl SearchSpace {
+    pub fn print(&self, data: &[PollColumn], format: &SlotFormat) {
+        for (day, &(available, options)) in data.iter().zip(&self.slots) {
+            println!(
+                "{}:\t{} available, {} ways to fill it",
+                format.format(&day.time),
+                available,
+                count(options)
+            );
+        }
+        println!();
+        println!("Schedules: {}", count(self.schedules));
+        println!("Shifts per person: at most {}", self.max_occur);
+        println!(
+            "Schedules within that: about {}, in {} search steps",
+            count(self.leaves),
+            count(self.nodes)
+        );
+        println!(
+            "Estimated time: {} (usually much less thanks to branch and bound)",
+            duration(self.seconds)
+        );
+    }
+}
+
+/// Estimate the size of the search space of a poll
+pub fn analyze(data: &[PollColumn], opts: &SchedulingOptions) -> SearchSpace {
+    let pinned = opts.pinned_names(data);
+    let slots = data
+        .iter()
+        .zip(&pinned)
+        .map(|(day, pinned)| {
+            let available = day
+                .responses
+                .iter()
+                .filter(|(person, response)| {
+                    !matches!(response, Response::No) || pinned.contains(&person.as_str())
+                })
+                .count();
+            let free = available.saturating_sub(pinned.len());
+            let places = opts.headcount_of(day).saturating_sub(pinned.len());
+            let options = if free < places && opts.ignore_empty_slots {
+                1.0
+            } else {
+                binomial(free, places)
+            };
+            (available, options)
+        })
+        .collect::<Vec<(usize, f64)>>();
+    let schedules = slots.iter().map(|&(_, options)| options).product();
+
+    let ctx = SearchContext::new(data, opts);
+    let mut rng = StdRng::seed_from_u64(opts.seed.unwrap_or(0));
+    let mut leaves = 0.0;
+    let mut nodes = 0.0;
+    let mut visited = 0;
+    let start = Instant::now();
+    for _ in 0..PROBES {
+        let mut schedule = Vec::new();
+        // The estimated number of nodes at the current depth
+        let mut width = 1.0;
+        nodes += width;
+        visited += 1;
+        while ctx.minimums_reachable(&schedule) {
+            if schedule.len() == data.len() {
+                leaves += width;
+                evaluate(schedule, data, opts);
+                break;
+            }
+            let mut options = ctx.slot_options(&schedule);
+            if options.is_empty() {
+                break;
+            }
+            width *= options.len() as f64;
+            nodes += width;
+            visited += 1;
+            schedule.push(options.swap_remove(rng.gen_range(0..options.len())));
+        }
+    }
+    let per_node = start.elapsed().as_secs_f64() / visited as f64;
+    let nodes = nodes / PROBES as f64;
+
+    SearchSpace {
+        slots,
+        max_occur: ctx.max_occur(),
+        schedules,
+        leaves: leaves / PROBES as f64,
+        nodes,
+        seconds: nodes * per_node / opts.thread_count() as f64,
+    }
+}
+
+/// The number of ways to choose `k` of `n` items, as a float to avoid overflows
+fn binomial(n: usize, k: usize) -> f64 {
+    if k > n {
+        return 0.0;
+    }
+    (0..k.min(n - k)).fold(1.0, |result, i| result * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Small counts in full, large ones in scientific notation
+fn count(value: f64) -> String {
+    if value < 1e6 {
+        format!("{}", value.round())
+    } else {
+        format!("{:.1e}", value)
+    }
+}
+
+/// A rough human-readable duration
+fn duration(seconds: f64) -> String {
+    let units = [
+        (365.0 * 24.0 * 3600.0, "years"),
+        (24.0 * 3600.0, "days"),
+        (3600.0, "hours"),
+        (60.0, "minutes"),
+    ];
+    for &(length, unit) in &units {
+        if seconds >= 2.0 * length {
+            return format!("{} {}", count(seconds / length), unit);
+        }
+    }
+    format!("{:.1} seconds", seconds)
+}
@@ -0,0 +1,245 @@
+//! # Simulated annealing
+//! A heuristic alternative to the exhaustive search, for polls that are too large for it.
+//!
+//! It minimizes the same cost function within `SchedulingOptions::time_limit`,
+//! but cannot guarantee that the schedules it finds are optimal.
+//! It also stops when `SchedulingOptions::cancel` is cancelled.
+
+use crate::data::*;
+use crate::progress::Tracker;
+use crate::scheduling::{
+    evaluate, BestSchedules, EvaluatedSchedule, NameOrder, ScheduleEntry, SchedulingOptions,
+};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+/// How long to search if `SchedulingOptions::time_limit` is not set
+pub const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Added to the cost for every violated hard constraint.
+///
+/// Violating schedules may be passed through, but never end up in the results.
+const VIOLATION_COST: f32 = 1000.0;
+
+/// The temperature at the start and at the end of the time limit
+const START_TEMPERATURE: f32 = 2.0;
+const END_TEMPERATURE: f32 = 0.01;
+
+/// Search for good schedules until the time limit is up
+pub fn compute_schedules<'data, 'b>(
+    data: &'data [PollColumn],
+    opts: &SchedulingOptions,
+) -> BestSchedules<'data, 'b>
+where
+    'data: 'b,
+{
+    let time_limit = opts.time_limit.unwrap_or(DEFAULT_TIME_LIMIT);
+    // The random choices are reproducible, but how many are made depends on the time limit
+    let mut rng = StdRng::seed_from_u64(opts.seed.unwrap_or(0));
+    let order = NameOrder::new(data, opts);
+    let pinned = opts.pinned_names(data);
+    let candidates = data
+        .iter()
+        .map(|day| {
+            day.responses
+                .iter()
+                .filter(|(_, response)| !matches!(response, Response::No))
+                .map(|(person, _)| person.as_str())
+                .collect::<Vec<&str>>()
+        })
+        .collect::<Vec<Vec<&str>>>();
+    let placeholders = data
+        .iter()
+        .map(|day| opts.placeholders_allowed(day))
+        .collect::<Vec<usize>>();
+
+    // Start with the pinned and random people, filling up with placeholders where necessary
+    let mut state = candidates
+        .iter()
+        .zip(&pinned)
+        .zip(data)
+        .map(|((available, pinned), day)| {
+            let mut others = available
+                .iter()
+                .copied()
+                .filter(|person| !pinned.contains(person))
+                .collect::<Vec<&str>>();
+            others.shuffle(&mut rng);
+            let mut names = pinned.clone();
+            names.append(&mut others);
+            names.resize(opts.headcount_of(day).max(pinned.len()), "??");
+            names.sort_unstable();
+            names
+        })
+        .collect::<Vec<Vec<&str>>>();
+    let (mut evaluated, mut violations) = evaluate_state(&state, data, opts);
+    let mut cost = evaluated.cost + VIOLATION_COST * violations as f32;
+    let mut best = BestSchedules::new(opts.top, opts.diversity);
+    let tracker = Tracker::new(opts.observer.as_deref(), 0);
+
+    let start = Instant::now();
+    loop {
+        if violations == 0 && !best.contains(&evaluated) {
+            best.add(evaluated.clone(), &order);
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= time_limit || opts.cancel.is_cancelled() {
+            break;
+        }
+        let progress = elapsed.as_secs_f32() / time_limit.as_secs_f32();
+        let temperature = START_TEMPERATURE * (END_TEMPERATURE / START_TEMPERATURE).powf(progress);
+
+        let mut next = state.clone();
+        if !mutate(
+            &mut next,
+            &candidates,
+            &pinned,
+            &placeholders,
+            opts,
+            &mut rng,
+        ) {
+            continue;
+        }
+        let (next_evaluated, next_violations) = evaluate_state(&next, data, opts);
+        tracker.evaluated(Some(next_evaluated.cost).filter(|_| next_violations == 0));
+        let next_cost = next_evaluated.cost + VIOLATION_COST * next_violations as f32;
+        if next_cost <= cost || rng.gen::<f32>() < ((cost - next_cost) / temperature).exp() {
+            state = next;
+            evaluated = next_evaluated;
+            violations = next_violations;
+            cost = next_cost;
+        }
+    }
+
+    best.unproven();
+    tracker.finish();
+    best
+}
+
+/// Randomly change one assignment, or swap two people between slots.
+///
+/// Pinned assignments are never changed, and slots get at most `placeholders` placeholders.
+/// Returns false if the chosen change was not possible.
+fn mutate<'a, R: Rng>(
+    state: &mut [Vec<&'a str>],
+    candidates: &[Vec<&'a str>],
+    pinned: &[Vec<&'a str>],
+    placeholders: &[usize],
+    opts: &SchedulingOptions,
+    rng: &mut R,
+) -> bool {
+    let slot = rng.gen_range(0..state.len());
+    if state[slot].is_empty() {
+        return false;
+    }
+    let position = rng.gen_range(0..state[slot].len());
+    if pinned[slot].contains(&state[slot][position]) {
+        return false;
+    }
+
+    if rng.gen::<bool>() {
+        let mut options = candidates[slot]
+            .iter()
+            .copied()
+            .filter(|person| !state[slot].contains(person) && below_max(state, person, opts))
+            .collect::<Vec<&str>>();
+        if state[slot][position] != "??" && count_placeholders(&state[slot]) < placeholders[slot] {
+            options.push("??");
+        }
+        match options.choose(rng) {
+            Some(person) => state[slot][position] = person,
+            None => return false,
+        }
+    } else {
+        let other = rng.gen_range(0..state.len());
+        if other == slot || state[other].is_empty() {
+            return false;
+        }
+        let other_position = rng.gen_range(0..state[other].len());
+        let (person, other_person) = (state[slot][position], state[other][other_position]);
+        if pinned[other].contains(&other_person) {
+            return false;
+        }
+        let fits = |person: &str, slot: usize| {
+            person == "??" || (candidates[slot].contains(&person) && !state[slot].contains(&person))
+        };
+        if !fits(other_person, slot) || !fits(person, other) {
+            return false;
+        }
+        // Swapping a placeholder for a person moves it to the other slot
+        let room = |slot: usize| count_placeholders(&state[slot]) < placeholders[slot];
+        if (other_person == "??" && person != "??" && !room(slot))
+            || (person == "??" && other_person != "??" && !room(other))
+        {
+            return false;
+        }
+        state[slot][position] = other_person;
+        state[other][other_position] = person;
+        state[other].sort_unstable();
+    }
+    state[slot].sort_unstable();
+    true
+}
+
+fn count_placeholders(names: &[&str]) -> usize {
+    names.iter().filter(|&&name| name == "??").count()
+}
+
+fn occurrences(state: &[Vec<&str>], person: &str) -> usize {
+    state.iter().filter(|names| names.contains(&person)).count()
+}
+
+fn below_max(state: &[Vec<&str>], person: &str, opts: &SchedulingOptions) -> bool {
+    match opts.limits.get(person).and_then(|limits| limits.max) {
+        Some(max) => occurrences(state, person) < max,
+        None => true,
+    }
+}
+
+/// Evaluate the schedule and count how many hard constraints it violates
+fn evaluate_state<'data, 'b>(
+    state: &[Vec<&'b str>],
+    data: &'data [PollColumn],
+    opts: &SchedulingOptions,
+) -> (EvaluatedSchedule<'data, 'b>, usize)
+where
+    'data: 'b,
+{
+    let mut violations = 0;
+    if !opts.ignore_empty_slots {
+        violations += state.iter().flatten().filter(|&&name| name == "??").count();
+    }
+    for (person, limits) in &opts.limits {
+        let occ = occurrences(state, person);
+        violations += limits.min.saturating_sub(occ);
+        violations += occ.saturating_sub(limits.max.unwrap_or(usize::MAX));
+    }
+    for (names, day) in state.iter().zip(data) {
+        if !opts.roles_covered(day, names) {
+            violations += 1;
+        }
+        violations += opts
+            .pairings
+            .iter()
+            .filter(|pairing| pairing.cost.is_none() && pairing.violated_by(names))
+            .count();
+    }
+    for (slot, names) in state.iter().enumerate() {
+        let upcoming = &state[slot + 1..(slot + opts.min_rest + 1).min(state.len())];
+        violations += names
+            .iter()
+            .filter(|&&name| name != "??")
+            .map(|name| upcoming.iter().filter(|other| other.contains(name)).count())
+            .sum::<usize>();
+    }
+
+    let schedule = state
+        .iter()
+        .zip(data)
+        .map(|(names, day)| ScheduleEntry::new(&day.time, names))
+        .collect();
+    (evaluate(schedule, data, opts), violations)
+}
@@ -3,18 +3,31 @@
 extern crate rocket;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use rocket::http::Status;
+use rocket::request::LenientForm;
+use rocket::response::status::Custom;
 use rocket::Data;
 use rocket::Response;
+use rocket::State;
 use rocket_contrib::json::Json;
 use rocket_cors::CorsOptions;
 use serde::Serialize;
 use simple_error::SimpleError;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use framaschedule::constraints::Pin;
+use framaschedule::data::{PollData, SlotFormat};
+use framaschedule::diagnostics;
 use framaschedule::framadate;
+use framaschedule::progress::{Observer, Progress};
 use framaschedule::scheduling;
 use framaschedule::scheduling::{
-    BestSchedules, EvaluatedSchedule, ScheduleEntry, SchedulingOptions,
+    CancelToken, CostWeights, Diversity, EvaluatedSchedule, SchedulingOptions, Spacing,
 };
 
 #[get("/hello")]
@@ -22,67 +35,385 @@ fn hello() -> &'static str {
     "Hello World!"
 }
 
-#[derive(Serialize)]
+const ISO_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// How many jobs may run at the same time, each using all cores
+const MAX_JOBS: usize = 4;
+
+/// How long a job may run at most, and by default
+const JOB_TIME_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+/// How long the results of a finished job are kept if nobody fetches them
+const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Clone)]
 struct ScheduleEntryResult {
     time: String,
+    /// ISO 8601 without time zone, if known
+    start: Option<String>,
+    end: Option<String>,
     name: String,
     ifneedbe: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
+struct TermCostResult {
+    term: String,
+    total: f32,
+    /// Empty if the term cannot be attributed to people
+    per_person: BTreeMap<String, f32>,
+}
+
+#[derive(Serialize, Clone)]
 struct ScheduleResult {
     cost: f32,
+    /// Whether the search proved that no better schedules exist, see `BestSchedules::is_optimal`
+    optimal: bool,
     name_counts: Vec<(String, usize)>,
+    loads: Vec<(String, f32)>,
+    breakdown: Vec<TermCostResult>,
     entries: Vec<ScheduleEntryResult>,
 }
 
-fn convert_schedule(mut old: EvaluatedSchedule) -> ScheduleResult {
+fn convert_schedule(mut old: EvaluatedSchedule, optimal: bool) -> ScheduleResult {
+    // Slots with several people are flattened into one entry per assignee
     ScheduleResult {
         cost: old.cost,
+        optimal,
         name_counts: old
             .name_counts
             .iter_mut()
             .map(|&mut (name, size)| (name.to_string(), size))
             .collect::<Vec<(String, usize)>>(),
+        loads: old
+            .loads
+            .iter()
+            .map(|&(name, load)| (name.to_string(), load))
+            .collect::<Vec<(String, f32)>>(),
+        breakdown: old
+            .breakdown
+            .drain(..)
+            .map(|term| TermCostResult {
+                term: term.term,
+                total: term.total,
+                per_person: term.per_person,
+            })
+            .collect(),
         entries: old
             .entries
-            .iter_mut()
-            .map(
-                |&mut ScheduleEntry {
-                     time,
-                     name,
-                     ifneedbe,
-                 }| ScheduleEntryResult {
-                    time: time.to_string(),
-                    name: name.to_string(),
-                    ifneedbe,
-                },
-            )
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .assignees
+                    .iter()
+                    .map(move |assignee| ScheduleEntryResult {
+                        time: entry.time.label.clone(),
+                        start: entry
+                            .time
+                            .start
+                            .map(|start| start.format(ISO_FORMAT).to_string()),
+                        end: entry.time.end.map(|end| end.format(ISO_FORMAT).to_string()),
+                        name: assignee.name.to_string(),
+                        ifneedbe: assignee.ifneedbe,
+                    })
+            })
             .collect::<Vec<ScheduleEntryResult>>(),
     }
 }
 
-#[post("/framadate", format = "text/csv", data = "<data>")]
-fn schedule(data: Data) -> Result<Json<ScheduleResult>, Box<dyn Error>> {
-    // Buffer file
-    let filename = rand::thread_rng()
+/// Optional query parameters to tune the scheduling
+#[derive(FromForm)]
+struct SchedulingParams {
+    weight_occurrences: Option<f32>,
+    weight_distance: Option<f32>,
+    weight_ifneedbe: Option<f32>,
+    weight_placeholder: Option<f32>,
+    weight_change: Option<f32>,
+    /// The number of people needed per slot
+    headcount: Option<usize>,
+    /// Comma-separated `slot:count` pairs overriding `headcount`, e.g. `3:2` for two people in the third slot
+    slot_headcounts: Option<String>,
+    top: Option<usize>,
+    min_difference: Option<usize>,
+    cost_tolerance: Option<f32>,
+    /// Comma-separated `slot:name` pairs, e.g. `1:Anna,3:Ben`
+    pins: Option<String>,
+    min_rest: Option<usize>,
+    /// `slots` or `days`
+    spacing: Option<String>,
+    /// In seconds, after which the best schedules found so far are returned
+    time_limit: Option<f64>,
+}
+
+impl SchedulingParams {
+    fn to_options(&self) -> Result<SchedulingOptions, SimpleError> {
+        let defaults = CostWeights::default();
+        let pins = match &self.pins {
+            Some(pins) => pins
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<Pin>, SimpleError>>()?,
+            None => Vec::new(),
+        };
+        Ok(SchedulingOptions {
+            weights: CostWeights {
+                occurrences: self.weight_occurrences.unwrap_or(defaults.occurrences),
+                distance: self.weight_distance.unwrap_or(defaults.distance),
+                ifneedbe: self.weight_ifneedbe.unwrap_or(defaults.ifneedbe),
+                placeholder: self.weight_placeholder.unwrap_or(defaults.placeholder),
+                change: self.weight_change.unwrap_or(defaults.change),
+            },
+            headcount: self.headcount.unwrap_or(1),
+            top: self.top.unwrap_or(2),
+            diversity: match (self.min_difference, self.cost_tolerance) {
+                (Some(min), _) => Diversity::MinDifference(min),
+                (None, Some(tolerance)) => Diversity::CostTolerance(tolerance),
+                (None, None) => Diversity::None,
+            },
+            pins,
+            min_rest: self.min_rest.unwrap_or(0),
+            spacing: match &self.spacing {
+                Some(spacing) => spacing.parse()?,
+                None => Spacing::Slots,
+            },
+            time_limit: match self.time_limit {
+                Some(seconds) => Some(Duration::try_from_secs_f64(seconds).map_err(|_| {
+                    SimpleError::new(format!("Invalid time limit: {} seconds", seconds))
+                })?),
+                None => None,
+            },
+            ..SchedulingOptions::default()
+        })
+    }
+
+    /// Set the headcounts of the slots given in `slot_headcounts`
+    fn apply_slot_headcounts(&self, data: &mut PollData) -> Result<(), SimpleError> {
+        let slots = data.len();
+        for slot_count in self.slot_headcounts.iter().flat_map(|s| s.split(',')) {
+            let invalid = || SimpleError::new(format!("Invalid slot headcount: '{}'", slot_count));
+            let mut parts = slot_count.splitn(2, ':');
+            let slot: usize = parts.next().unwrap().parse().map_err(|_| invalid())?;
+            let count = parts
+                .next()
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?;
+            let column = slot
+                .checked_sub(1)
+                .and_then(|i| data.get_mut(i))
+                .ok_or_else(|| {
+                    SimpleError::new(format!(
+                        "Slot {} does not exist, there are {} slots",
+                        slot, slots
+                    ))
+                })?;
+            column.headcount = Some(count);
+        }
+        Ok(())
+    }
+}
+
+fn random_id() -> String {
+    rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .map(char::from)
         .take(15)
-        .collect::<String>();
-    let filename = format!("/tmp/schedule{}.csv", &filename);
+        .collect::<String>()
+}
+
+/// Read the uploaded poll, with the slot headcounts of the parameters
+fn read_upload(data: Data, params: &SchedulingParams) -> Result<PollData, Box<dyn Error>> {
+    // Buffer file
+    let filename = format!("/tmp/schedule{}.csv", random_id());
     data.stream_to_file(&filename)?;
 
-    // Schedule
-    let schedule_data = framadate::read_data(&filename)?;
+    let mut schedule_data = framadate::read_data(&filename)?;
     std::fs::remove_file(&filename)?;
-    let result = scheduling::compute_all_schedules(&schedule_data, &SchedulingOptions::default());
+    params.apply_slot_headcounts(&mut schedule_data)?;
+    Ok(schedule_data)
+}
+
+/// Schedule the poll, returning the best schedules or an error if there are none
+fn compute_schedules(
+    schedule_data: &PollData,
+    options: &SchedulingOptions,
+) -> Result<Vec<ScheduleResult>, Box<dyn Error>> {
+    scheduling::check_constraints(schedule_data, options)?;
+    let result = scheduling::compute_all_schedules(schedule_data, options);
 
     // TODO: handle invalid schedules better (rocket-wise)
-    match result {
-        BestSchedules::Two(r1, _) | BestSchedules::One(r1) => Ok(Json(convert_schedule(r1))),
-        _ => Err(Box::from(SimpleError::new("No valid schedule found!"))),
+    if result.is_empty() {
+        let diagnosis = diagnostics::diagnose(schedule_data, options);
+        let reasons = diagnosis.describe(schedule_data, &SlotFormat::Label);
+        return Err(Box::from(SimpleError::new(format!(
+            "No valid schedule found! {}",
+            reasons.join(". ")
+        ))));
     }
+    let optimal = result.is_optimal();
+    Ok(result
+        .into_vec()
+        .into_iter()
+        .map(|schedule| convert_schedule(schedule, optimal))
+        .collect())
+}
+
+#[post("/framadate?<params..>", format = "text/csv", data = "<data>")]
+fn schedule(
+    data: Data,
+    params: LenientForm<SchedulingParams>,
+) -> Result<Json<ScheduleResult>, Box<dyn Error>> {
+    let best = compute_schedules(&read_upload(data, &params)?, &params.to_options()?)?
+        .into_iter()
+        .next()
+        .unwrap();
+    Ok(Json(best))
+}
+
+/// Like `schedule`, but returns the `top` best schedules
+#[post("/framadate/top?<params..>", format = "text/csv", data = "<data>")]
+fn schedule_top(
+    data: Data,
+    params: LenientForm<SchedulingParams>,
+) -> Result<Json<Vec<ScheduleResult>>, Box<dyn Error>> {
+    Ok(Json(compute_schedules(
+        &read_upload(data, &params)?,
+        &params.to_options()?,
+    )?))
+}
+
+#[derive(Serialize, Clone)]
+struct ProgressResult {
+    branches_done: usize,
+    branches: usize,
+    schedules: u64,
+    best_cost: Option<f32>,
+    /// In seconds
+    elapsed: f32,
+}
+
+/// The state of a job started with `start_job`
+#[derive(Serialize, Clone, Default)]
+struct JobStatus {
+    /// The latest progress report, see `progress::Progress`
+    progress: Option<ProgressResult>,
+    finished: bool,
+    /// The best schedules once the job is finished, like `schedule_top` returns them
+    schedules: Option<Vec<ScheduleResult>>,
+    error: Option<String>,
+    #[serde(skip)]
+    cancel: CancelToken,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+
+/// The running and finished jobs, by id
+#[derive(Default)]
+struct Jobs(Mutex<HashMap<String, Arc<Mutex<JobStatus>>>>);
+
+/// Forget the finished jobs whose results were not fetched within `JOB_TTL`
+fn expire_jobs(jobs: &mut HashMap<String, Arc<Mutex<JobStatus>>>) {
+    jobs.retain(|_, status| match status.lock().unwrap().finished_at {
+        Some(finished_at) => finished_at.elapsed() < JOB_TTL,
+        None => true,
+    });
+}
+
+/// Records the progress of a job in its status
+struct JobObserver(Arc<Mutex<JobStatus>>);
+
+impl fmt::Debug for JobObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("JobObserver")
+    }
+}
+
+impl Observer for JobObserver {
+    fn progress(&self, progress: &Progress) {
+        self.0.lock().unwrap().progress = Some(ProgressResult {
+            branches_done: progress.branches_done,
+            branches: progress.branches,
+            schedules: progress.schedules,
+            best_cost: progress.best_cost,
+            elapsed: progress.elapsed.as_secs_f32(),
+        });
+    }
+}
+
+/// Like `schedule_top`, but schedules in the background and returns the id of the job right away.
+///
+/// Jobs stop after `JOB_TIME_LIMIT` at most, and while `MAX_JOBS` are running, no more are started.
+#[post("/framadate/jobs?<params..>", format = "text/csv", data = "<data>")]
+fn start_job(
+    data: Data,
+    params: LenientForm<SchedulingParams>,
+    jobs: State<Jobs>,
+) -> Result<Custom<String>, Box<dyn Error>> {
+    let schedule_data = read_upload(data, &params)?;
+    let mut options = params.to_options()?;
+    options.time_limit = Some(match options.time_limit {
+        Some(time_limit) => time_limit.min(JOB_TIME_LIMIT),
+        None => JOB_TIME_LIMIT,
+    });
+    let status = Arc::new(Mutex::new(JobStatus::default()));
+    options.cancel = status.lock().unwrap().cancel.clone();
+    options.observer = Some(Box::new(JobObserver(status.clone())));
+
+    let id = random_id();
+    {
+        let mut jobs = jobs.0.lock().unwrap();
+        expire_jobs(&mut jobs);
+        let running = jobs
+            .values()
+            .filter(|status| status.lock().unwrap().finished_at.is_none())
+            .count();
+        if running >= MAX_JOBS {
+            return Ok(Custom(
+                Status::ServiceUnavailable,
+                String::from("Too many jobs are running, try again later"),
+            ));
+        }
+        jobs.insert(id.clone(), status.clone());
+    }
+    thread::spawn(move || {
+        let result = compute_schedules(&schedule_data, &options);
+        let mut status = status.lock().unwrap();
+        match result {
+            Ok(schedules) => status.schedules = Some(schedules),
+            Err(error) => status.error = Some(error.to_string()),
+        }
+        status.finished = true;
+        status.finished_at = Some(Instant::now());
+    });
+    Ok(Custom(Status::Ok, id))
+}
+
+/// The progress of a job, or its results once it is finished.
+///
+/// Finished jobs are forgotten once their results have been fetched, or after `JOB_TTL`.
+#[get("/jobs/<id>")]
+fn job_status(id: String, jobs: State<Jobs>) -> Option<Json<JobStatus>> {
+    let mut jobs = jobs.0.lock().unwrap();
+    expire_jobs(&mut jobs);
+    let status = jobs.get(&id)?.lock().unwrap().clone();
+    if status.finished {
+        jobs.remove(&id);
+    }
+    Some(Json(status))
+}
+
+/// Stop a job, which then finishes with the best schedules found so far
+#[delete("/jobs/<id>")]
+fn cancel_job(id: String, jobs: State<Jobs>) -> Option<()> {
+    jobs.0
+        .lock()
+        .unwrap()
+        .get(&id)?
+        .lock()
+        .unwrap()
+        .cancel
+        .cancel();
+    Some(())
 }
 
 #[options("/framadate")]
@@ -97,7 +428,18 @@ fn options_handler<'a>() -> Response<'a> {
 fn main() -> Result<(), Box<dyn Error>> {
     let cors = CorsOptions::default().to_cors()?;
     rocket::ignite()
-        .mount("/scheduling/api", routes![hello, schedule])
+        .manage(Jobs::default())
+        .mount(
+            "/scheduling/api",
+            routes![
+                hello,
+                schedule,
+                schedule_top,
+                start_job,
+                job_status,
+                cancel_job
+            ],
+        )
         .attach(cors)
         .launch();
 
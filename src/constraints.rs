@@ -0,0 +1,225 @@
+//! # Scheduling constraints
+//! This module describes hard constraints on schedules that are not part of the poll data itself.
+
+use crate::data::*;
+use crate::scheduling::Load;
+use csv::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::str::FromStr;
+
+/// The minimum and maximum number of shifts one person may be assigned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShiftLimits {
+    pub min: usize,
+    /// No maximum if `None`
+    pub max: Option<usize>,
+}
+
+/// A person who must take a particular slot, regardless of the cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pin {
+    /// The index of the slot, starting from 0
+    pub slot: usize,
+    pub name: Name,
+}
+
+impl FromStr for Pin {
+    type Err = SimpleError;
+
+    /// Parses `slot:name`, where slots are numbered starting from 1 as on the command line
+    fn from_str(s: &str) -> Result<Pin, SimpleError> {
+        let invalid = || SimpleError::new(format!("Invalid pin: '{}'", s));
+        let mut parts = s.splitn(2, ':');
+        let slot: usize = parts
+            .next()
+            .unwrap()
+            .trim()
+            .parse()
+            .map_err(|_| invalid())?;
+        let name = parts.next().ok_or_else(invalid)?.trim();
+        if slot == 0 || name.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Pin {
+            slot: slot - 1,
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// Whether two people should share their shifts or never work together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairingKind {
+    /// Each of them only takes slots the other takes as well
+    Together,
+    /// They never take the same slot
+    Apart,
+}
+
+/// A constraint between two people, e.g. a couple who want to work together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pairing {
+    pub kind: PairingKind,
+    pub first: Name,
+    pub second: Name,
+    /// The cost of every slot violating the pairing, or `None` if it must never be violated
+    pub cost: Option<f32>,
+}
+
+impl Pairing {
+    /// Parses `first,second` or `first,second,cost`
+    pub fn parse(kind: PairingKind, s: &str) -> Result<Pairing, SimpleError> {
+        let invalid = || SimpleError::new(format!("Invalid pairing: '{}'", s));
+        let parts = s.split(',').map(str::trim).collect::<Vec<&str>>();
+        let cost = match parts.get(2) {
+            Some(cost) => Some(cost.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() || parts[1].is_empty() {
+            return Err(invalid());
+        }
+        Ok(Pairing {
+            kind,
+            first: parts[0].to_owned(),
+            second: parts[1].to_owned(),
+            cost,
+        })
+    }
+
+    /// Whether the people assigned to a slot violate the pairing
+    pub fn violated_by(&self, names: &[&str]) -> bool {
+        let first = names.contains(&self.first.as_str());
+        let second = names.contains(&self.second.as_str());
+        match self.kind {
+            PairingKind::Together => first != second,
+            PairingKind::Apart => first && second,
+        }
+    }
+}
+
+/// Reads per-person shift limits.
+///
+/// The file needs a `name,min,max` header, and either limit may be left empty.
+/// See `res/test/test_limits.csv` for an example.
+pub fn read_limits(file_name: &str) -> Result<HashMap<Name, ShiftLimits>, Box<dyn Error>> {
+    let mut limits = HashMap::new();
+    let mut rdr = Reader::from_path(file_name)?;
+
+    for r in rdr.records() {
+        let r = r?;
+        let name = r.get(0).unwrap_or("").trim();
+        if name.is_empty() {
+            return Err(Box::from(SimpleError::new("Shift limits need a name")));
+        }
+        let parse = |field: Option<&str>| -> Result<Option<usize>, Box<dyn Error>> {
+            match field.map(str::trim) {
+                None | Some("") => Ok(None),
+                Some(n) => Ok(Some(n.parse().map_err(|_| {
+                    SimpleError::new(format!("Invalid shift limit for {}: '{}'", name, n))
+                })?)),
+            }
+        };
+        limits.insert(
+            name.to_owned(),
+            ShiftLimits {
+                min: parse(r.get(1))?.unwrap_or(0),
+                max: parse(r.get(2))?,
+            },
+        );
+    }
+
+    Ok(limits)
+}
+
+/// Reads the roles of each person.
+///
+/// The file needs a `name,role` header and has one line per role, so people may appear several times.
+/// See `res/test/test_roles.csv` for an example.
+pub fn read_roles(file_name: &str) -> Result<HashMap<Name, Vec<Role>>, Box<dyn Error>> {
+    let mut roles: HashMap<Name, Vec<Role>> = HashMap::new();
+    let mut rdr = Reader::from_path(file_name)?;
+
+    for r in rdr.records() {
+        let r = r?;
+        let (name, role) = (r.get(0).unwrap_or("").trim(), r.get(1).unwrap_or("").trim());
+        if name.is_empty() || role.is_empty() {
+            return Err(Box::from(SimpleError::new("Roles need a name and a role")));
+        }
+        roles
+            .entry(name.to_owned())
+            .or_default()
+            .push(role.to_owned());
+    }
+
+    Ok(roles)
+}
+
+/// Reads a schedule written by `EvaluatedSchedule::write_csv`, e.g. to reschedule after changes.
+///
+/// The slots are matched using the `format` the schedule was written with,
+/// and the assignments are returned as pins to the slots of `data`, skipping placeholders.
+pub fn read_schedule(
+    file_name: &str,
+    data: &[PollColumn],
+    format: &SlotFormat,
+) -> Result<Vec<Pin>, Box<dyn Error>> {
+    let mut assignments = Vec::new();
+    let mut rdr = Reader::from_path(file_name)?;
+
+    for r in rdr.records() {
+        let r = r?;
+        let (time, name) = (r.get(0).unwrap_or(""), r.get(1).unwrap_or("").trim());
+        let slot = data
+            .iter()
+            .position(|day| format.format(&day.time) == time)
+            .ok_or_else(|| SimpleError::new(format!("Unknown slot in schedule: '{}'", time)))?;
+        if name.is_empty() || name == "??" {
+            continue;
+        }
+        assignments.push(Pin {
+            slot,
+            name: name.to_owned(),
+        });
+    }
+
+    Ok(assignments)
+}
+
+/// Reads each person's workload in earlier polls, to be added with `add_history`.
+///
+/// The file is either a schedule written by `EvaluatedSchedule::write_csv`, which counts shifts,
+/// or a JSON object with the total of each person if its name ends with `.json`, e.g. `{"Anna": 3}`,
+/// which must be measured in the same `load` unit as the poll.
+pub fn read_history(file_name: &str, load: Load) -> Result<HashMap<Name, f32>, Box<dyn Error>> {
+    if file_name.ends_with(".json") {
+        return Ok(serde_json::from_reader(File::open(file_name)?)?);
+    }
+    // Exported schedules do not contain the lengths of their slots
+    if load == Load::Hours {
+        return Err(Box::from(SimpleError::new(format!(
+            "{} counts shifts, but the workload is measured in hours; use a JSON file with hours instead",
+            file_name
+        ))));
+    }
+
+    let mut history = HashMap::new();
+    let mut rdr = Reader::from_path(file_name)?;
+    for r in rdr.records() {
+        let r = r?;
+        let name = r.get(1).unwrap_or("").trim();
+        if name.is_empty() || name == "??" {
+            continue;
+        }
+        *history.entry(name.to_owned()).or_insert(0.0) += 1.0;
+    }
+
+    Ok(history)
+}
+
+/// Adds the totals of another history, e.g. of the schedule of one more month
+pub fn add_history(history: &mut HashMap<Name, f32>, other: HashMap<Name, f32>) {
+    for (name, total) in other {
+        *history.entry(name).or_insert(0.0) += total;
+    }
+}
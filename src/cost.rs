@@ -0,0 +1,631 @@
+//! # Cost function
+//! The cost of a schedule is the sum of several terms, each implementing `CostTerm`.
+//!
+//! The built-in terms are weighted by `SchedulingOptions::weights`,
+//! and further terms can be added to `SchedulingOptions::cost_terms`.
+
+use crate::data::*;
+use crate::scheduling::{ScheduleEntry, SchedulingOptions};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::Debug;
+
+/// One term of the cost function that is minimized by the scheduling.
+///
+/// Terms are shared between the scheduling threads, so they must be `Send + Sync`.
+/// Costs must never be negative, otherwise the search may prune optimal schedules.
+pub trait CostTerm: Debug + Send + Sync {
+    /// A short, human-readable name of the term
+    fn name(&self) -> &str;
+
+    /// The cost of a complete schedule.
+    ///
+    /// The `ifneedbe` flags of the assignees are already set when this is called.
+    fn cost(
+        &self,
+        schedule: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32;
+
+    /// The cost of a complete schedule broken down per person, adding up to `cost`.
+    ///
+    /// This explains the results, so it is only called for them.
+    /// The default of `None` means that the cost cannot be attributed to people.
+    fn cost_per_person(
+        &self,
+        _schedule: &[ScheduleEntry],
+        _data: &[PollColumn],
+        _opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        None
+    }
+
+    /// A lower bound on the cost of every complete schedule starting with `partial`.
+    ///
+    /// The search skips partial schedules that cannot beat the ones found so far,
+    /// so tighter bounds make it faster. The default of 0 is always valid, but never prunes.
+    /// Unlike in `cost`, the `ifneedbe` flags are not set yet.
+    fn lower_bound(
+        &self,
+        _partial: &[ScheduleEntry],
+        _data: &[PollColumn],
+        _opts: &SchedulingOptions,
+    ) -> f32 {
+        0.0
+    }
+}
+
+/// The built-in cost terms, in decreasing order of their default weight
+pub fn builtin_terms() -> Vec<Box<dyn CostTerm>> {
+    vec![
+        Box::new(Occurrences),
+        Box::new(Distance),
+        Box::new(IfNeedBe),
+        Box::new(Placeholders),
+        Box::new(Changes),
+        Box::new(Pairings),
+    ]
+}
+
+/// The contribution of one cost term to the cost of a schedule
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermCost {
+    /// See `CostTerm::name`
+    pub term: String,
+    pub total: f32,
+    /// Empty if the term cannot be attributed to people
+    pub per_person: BTreeMap<Name, f32>,
+}
+
+/// The contribution of each term in `SchedulingOptions::cost_terms` to the cost of a complete schedule
+pub fn breakdown(
+    schedule: &[ScheduleEntry],
+    data: &[PollColumn],
+    opts: &SchedulingOptions,
+) -> Vec<TermCost> {
+    opts.cost_terms
+        .iter()
+        .map(|term| TermCost {
+            term: term.name().to_owned(),
+            total: term.cost(schedule, data, opts),
+            per_person: term
+                .cost_per_person(schedule, data, opts)
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// The number of shifts of each person, including placeholders
+pub fn occurrences<'b>(schedule: &[ScheduleEntry<'_, 'b>]) -> HashMap<&'b str, usize> {
+    let mut person_occurrences = HashMap::new();
+    for person in schedule
+        .iter()
+        .flat_map(|e| e.assignees.iter().map(|a| a.name))
+    {
+        *person_occurrences.entry(person).or_insert(0) += 1;
+    }
+    person_occurrences
+}
+
+/// The workload of each person, including placeholders, see `SchedulingOptions::load_of`
+pub fn loads<'b>(
+    schedule: &[ScheduleEntry<'_, 'b>],
+    data: &[PollColumn],
+    opts: &SchedulingOptions,
+) -> BTreeMap<&'b str, f32> {
+    // Ordered, so that the floating point sums do not depend on the hash seed
+    let mut person_loads = BTreeMap::new();
+    for (entry, day) in schedule.iter().zip(data) {
+        let load = opts.load_of(day);
+        for assignee in &entry.assignees {
+            *person_loads.entry(assignee.name).or_insert(0.0) += load;
+        }
+    }
+    person_loads
+}
+
+/// Adds up a fixed cost for every time a person is named
+fn per_person<'a>(names: impl Iterator<Item = &'a str>, cost: f32) -> BTreeMap<Name, f32> {
+    let mut costs = BTreeMap::new();
+    for name in names {
+        *costs.entry(name.to_owned()).or_insert(0.0) += cost;
+    }
+    costs
+}
+
+/// Equal shift distribution: the square of each person's workload,
+/// which is their number of shifts unless slots are weighted
+#[derive(Debug)]
+pub struct Occurrences;
+
+impl CostTerm for Occurrences {
+    fn name(&self) -> &str {
+        "occurrences"
+    }
+
+    fn cost(
+        &self,
+        schedule: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        let squares: f32 = loads(schedule, data, opts)
+            .into_iter()
+            .map(|(person, load)| cumulative_square(opts.history_of(person), load))
+            .sum();
+        opts.weights.occurrences * squares
+    }
+
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let squares = loads(schedule, data, opts)
+            .into_iter()
+            .map(|(person, load)| {
+                let square = cumulative_square(opts.history_of(person), load);
+                (person.to_owned(), opts.weights.occurrences * square)
+            });
+        Some(squares.collect())
+    }
+
+    /// Hands out the remaining places to whoever has the fewest shifts so far,
+    /// which is optimal if everyone could take any of the slots they are available for.
+    /// If slots are weighted differently or there is a history, the remaining workload is split evenly instead.
+    fn lower_bound(
+        &self,
+        partial: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        if !opts.uniform_load(data) || !opts.history.is_empty() {
+            return opts.weights.occurrences * balanced_squares(partial, data, opts);
+        }
+
+        let mut counts = occurrences(partial);
+        let mut capacity = HashMap::new();
+        let mut open = 0;
+        for day in &data[partial.len()..] {
+            let headcount = opts.headcount_of(day);
+            open += headcount;
+            for (person, response) in &day.responses {
+                if !matches!(response, Response::No) {
+                    *capacity.entry(person.as_str()).or_insert(0) += 1;
+                }
+            }
+            if opts.ignore_empty_slots {
+                *capacity.entry("??").or_insert(0) += headcount;
+            }
+        }
+
+        let mut heap = capacity
+            .into_iter()
+            .map(|(person, cap)| Reverse((*counts.get(person).unwrap_or(&0), person, cap)))
+            .collect::<BinaryHeap<_>>();
+        while open > 0 {
+            match heap.pop() {
+                Some(Reverse((count, person, cap))) => {
+                    open -= 1;
+                    counts.insert(person, count + 1);
+                    if cap > 1 {
+                        heap.push(Reverse((count + 1, person, cap - 1)));
+                    }
+                }
+                // Not even the remaining places can be filled
+                None => return f32::INFINITY,
+            }
+        }
+
+        let squares: usize = counts.values().map(|occ| occ * occ).sum();
+        let load = data.first().map_or(1.0, |day| opts.load_of(day));
+        opts.weights.occurrences * squares as f32 * load * load
+    }
+}
+
+/// The square of a person's total workload including their `history`, without the square of the history,
+/// which is the same for every schedule
+fn cumulative_square(history: f32, load: f32) -> f32 {
+    load * (load + 2.0 * history)
+}
+
+/// The smallest sum of `cumulative_square`s if the remaining workload could be split arbitrarily,
+/// which raises the lowest total workloads to a common level as far as people are available
+fn balanced_squares(
+    partial: &[ScheduleEntry],
+    data: &[PollColumn],
+    opts: &SchedulingOptions,
+) -> f32 {
+    let mut loads = loads(partial, data, opts);
+    let mut capacity = BTreeMap::new();
+    let mut open = 0.0;
+    for day in &data[partial.len()..] {
+        let load = opts.load_of(day);
+        let headcount = opts.headcount_of(day) as f32;
+        open += load * headcount;
+        for (person, response) in &day.responses {
+            if !matches!(response, Response::No) {
+                *capacity.entry(person.as_str()).or_insert(0.0) += load;
+            }
+        }
+        if opts.ignore_empty_slots {
+            *capacity.entry("??").or_insert(0.0) += load * headcount;
+        }
+    }
+    let people = capacity
+        .into_iter()
+        .map(|(person, cap)| {
+            let load = loads.remove(person).unwrap_or(0.0);
+            (opts.history_of(person), load, cap)
+        })
+        .collect::<Vec<(f32, f32, f32)>>();
+    let added = |level: f32, (history, load, cap): (f32, f32, f32)| -> f32 {
+        (level - history - load).max(0.0).min(cap)
+    };
+    let filled = |level: f32| -> f32 { people.iter().map(|&person| added(level, person)).sum() };
+
+    // Find the level by bisection, rounding down to keep the bound valid
+    let (mut low, mut high) = (
+        0.0,
+        people
+            .iter()
+            .map(|&(history, load, cap)| history + load + cap)
+            .fold(0.0, f32::max),
+    );
+    if filled(high) < open - LOAD_TOLERANCE {
+        // Not even the remaining places can be filled
+        return f32::INFINITY;
+    }
+    for _ in 0..BISECTION_STEPS {
+        let middle = (low + high) / 2.0;
+        if filled(middle) < open {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    let raised: f32 = people
+        .iter()
+        .map(|&person| {
+            let (history, load, _) = person;
+            cumulative_square(history, load + added(low, person))
+        })
+        .sum();
+    let others: f32 = loads
+        .into_iter()
+        .map(|(person, load)| cumulative_square(opts.history_of(person), load))
+        .sum();
+    raised + others
+}
+
+/// Slack for rounding errors when summing workloads
+const LOAD_TOLERANCE: f32 = 1e-3;
+
+/// Enough to find the level of `balanced_squares` up to the precision of `f32`
+const BISECTION_STEPS: usize = 40;
+
+/// Equal distance between shifts: the inverse square of each person's average distance,
+/// measured according to `SchedulingOptions::spacing`
+#[derive(Debug)]
+pub struct Distance;
+
+impl CostTerm for Distance {
+    fn name(&self) -> &str {
+        "distance"
+    }
+
+    fn cost(
+        &self,
+        schedule: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        let components = avg_distance_components(schedule, &opts.slot_positions(data));
+        opts.weights.distance * components.values().sum::<f32>()
+    }
+
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let components = avg_distance_components(schedule, &opts.slot_positions(data));
+        let costs = components
+            .into_iter()
+            .map(|(person, component)| (person.to_owned(), opts.weights.distance * component));
+        Some(costs.collect())
+    }
+}
+
+/// Each person's inverse square average distance between shifts, where `positions` locates each slot
+fn avg_distance_components<'b>(
+    s: &[ScheduleEntry<'_, 'b>],
+    positions: &[f32],
+) -> BTreeMap<&'b str, f32> {
+    let mut last_seen = HashMap::new();
+    // Ordered, so that the floating point sum does not depend on the hash seed
+    let mut dsts = BTreeMap::new();
+
+    let assignments = s
+        .iter()
+        .enumerate()
+        .flat_map(|(i, e)| e.assignees.iter().map(move |a| (i, a.name)));
+    for (i, person) in assignments {
+        let last_seen_i = last_seen.entry(person).or_insert(i);
+        let dsts = dsts.entry(person).or_insert_with(Vec::new);
+        let dst = positions[i] - positions[*last_seen_i];
+        if dst > 0.0 {
+            dsts.push(dst);
+        }
+        last_seen.insert(person, i);
+    }
+
+    let mut result = BTreeMap::new();
+    for (person, dsts) in dsts {
+        let dst_sum: f32 = dsts.iter().sum();
+        let avg_dst = dst_sum / dsts.len() as f32;
+        if avg_dst > 0.0 {
+            result.insert(person, 1.0 / (avg_dst * avg_dst));
+        }
+    }
+    result
+}
+
+/// Minimal use of IfNeedBe responses: a fixed cost for every use
+#[derive(Debug)]
+pub struct IfNeedBe;
+
+impl CostTerm for IfNeedBe {
+    fn name(&self) -> &str {
+        "ifneedbe"
+    }
+
+    fn cost(&self, schedule: &[ScheduleEntry], _: &[PollColumn], opts: &SchedulingOptions) -> f32 {
+        let uses = schedule
+            .iter()
+            .flat_map(|e| e.assignees.iter())
+            .filter(|a| a.ifneedbe)
+            .count();
+        opts.weights.ifneedbe * uses as f32
+    }
+
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        _: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let uses = schedule
+            .iter()
+            .flat_map(|e| e.assignees.iter())
+            .filter(|a| a.ifneedbe)
+            .map(|a| a.name);
+        Some(per_person(uses, opts.weights.ifneedbe))
+    }
+
+    /// Counts the uses so far and those forced by too few Yes responses in the remaining slots.
+    ///
+    /// With `SchedulingOptions::ignore_empty_slots`, a placeholder may take the place of a forced use.
+    fn lower_bound(
+        &self,
+        partial: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        let mut uses = 0;
+        for (entry, day) in partial.iter().zip(data) {
+            uses += entry
+                .assignees
+                .iter()
+                .filter(|a| matches!(day.responses.get(a.name), Some(Response::IfNeedBe)))
+                .count();
+        }
+        let mut forced = 0;
+        for day in &data[partial.len()..] {
+            let (yes, available) = response_counts(day);
+            forced += opts.headcount_of(day).min(available).saturating_sub(yes);
+        }
+        let forced_weight = if opts.ignore_empty_slots {
+            opts.weights.ifneedbe.min(opts.weights.placeholder)
+        } else {
+            opts.weights.ifneedbe
+        };
+        opts.weights.ifneedbe * uses as f32 + forced_weight * forced as f32
+    }
+}
+
+/// The number of Yes responses and of Yes or IfNeedBe responses for a slot
+fn response_counts(day: &PollColumn) -> (usize, usize) {
+    let yes = day
+        .responses
+        .values()
+        .filter(|r| matches!(r, Response::Yes))
+        .count();
+    let ifneedbe = day
+        .responses
+        .values()
+        .filter(|r| matches!(r, Response::IfNeedBe))
+        .count();
+    (yes, yes + ifneedbe)
+}
+
+/// Penalize using placeholders: a fixed cost for every `??`
+#[derive(Debug)]
+pub struct Placeholders;
+
+impl CostTerm for Placeholders {
+    fn name(&self) -> &str {
+        "placeholders"
+    }
+
+    fn cost(&self, schedule: &[ScheduleEntry], _: &[PollColumn], opts: &SchedulingOptions) -> f32 {
+        let uses = schedule
+            .iter()
+            .flat_map(|e| e.assignees.iter())
+            .filter(|a| a.name == "??")
+            .count();
+        opts.weights.placeholder * uses as f32
+    }
+
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        _: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let uses = schedule
+            .iter()
+            .flat_map(|e| e.assignees.iter())
+            .filter(|a| a.name == "??")
+            .map(|a| a.name);
+        Some(per_person(uses, opts.weights.placeholder))
+    }
+
+    /// Counts the placeholders so far and those forced by too few responses in the remaining slots
+    fn lower_bound(
+        &self,
+        partial: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        let mut uses = partial
+            .iter()
+            .flat_map(|e| e.assignees.iter())
+            .filter(|a| a.name == "??")
+            .count();
+        for day in &data[partial.len()..] {
+            let (_, available) = response_counts(day);
+            uses += opts.headcount_of(day).saturating_sub(available);
+        }
+        opts.weights.placeholder * uses as f32
+    }
+}
+
+/// Stability when rescheduling: a fixed cost for every changed assignment of `SchedulingOptions::previous`
+#[derive(Debug)]
+pub struct Changes;
+
+impl CostTerm for Changes {
+    fn name(&self) -> &str {
+        "changes"
+    }
+
+    fn cost(&self, schedule: &[ScheduleEntry], _: &[PollColumn], opts: &SchedulingOptions) -> f32 {
+        let changes = opts
+            .previous
+            .iter()
+            .filter(|pin| !schedule[pin.slot].contains(&pin.name))
+            .count();
+        opts.weights.change * changes as f32
+    }
+
+    /// Attributes every change to the person who lost the assignment
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        _: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let changed = opts
+            .previous
+            .iter()
+            .filter(|pin| !schedule[pin.slot].contains(&pin.name))
+            .map(|pin| pin.name.as_str());
+        Some(per_person(changed, opts.weights.change))
+    }
+
+    /// Counts the changes so far and the people who are no longer available for their later slots
+    fn lower_bound(
+        &self,
+        partial: &[ScheduleEntry],
+        data: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        let changes = opts
+            .previous
+            .iter()
+            .filter(|pin| match partial.get(pin.slot) {
+                Some(entry) => !entry.contains(&pin.name),
+                None => !matches!(
+                    data[pin.slot].responses.get(&pin.name),
+                    Some(Response::Yes) | Some(Response::IfNeedBe)
+                ),
+            })
+            .count();
+        opts.weights.change * changes as f32
+    }
+}
+
+/// Soft pairings: the cost of each pairing for every slot that violates it.
+///
+/// Pairings without a cost are hard constraints, which the solvers never violate.
+#[derive(Debug)]
+pub struct Pairings;
+
+impl Pairings {
+    fn violations(schedule: &[ScheduleEntry], opts: &SchedulingOptions) -> f32 {
+        let mut cost = 0.0;
+        for entry in schedule {
+            let names = entry
+                .assignees
+                .iter()
+                .map(|a| a.name)
+                .collect::<Vec<&str>>();
+            cost += opts
+                .pairings
+                .iter()
+                .filter(|pairing| pairing.violated_by(&names))
+                .filter_map(|pairing| pairing.cost)
+                .sum::<f32>();
+        }
+        cost
+    }
+}
+
+impl CostTerm for Pairings {
+    fn name(&self) -> &str {
+        "pairings"
+    }
+
+    fn cost(&self, schedule: &[ScheduleEntry], _: &[PollColumn], opts: &SchedulingOptions) -> f32 {
+        Pairings::violations(schedule, opts)
+    }
+
+    /// Splits the cost of every violation between the two people
+    fn cost_per_person(
+        &self,
+        schedule: &[ScheduleEntry],
+        _: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> Option<BTreeMap<Name, f32>> {
+        let mut costs = BTreeMap::new();
+        for entry in schedule {
+            let names = entry
+                .assignees
+                .iter()
+                .map(|a| a.name)
+                .collect::<Vec<&str>>();
+            for pairing in opts.pairings.iter().filter(|p| p.violated_by(&names)) {
+                let cost = pairing.cost.unwrap_or(0.0) / 2.0;
+                for person in &[&pairing.first, &pairing.second] {
+                    *costs.entry((*person).clone()).or_insert(0.0) += cost;
+                }
+            }
+        }
+        Some(costs)
+    }
+
+    /// Counts the violations so far
+    fn lower_bound(
+        &self,
+        partial: &[ScheduleEntry],
+        _: &[PollColumn],
+        opts: &SchedulingOptions,
+    ) -> f32 {
+        Pairings::violations(partial, opts)
+    }
+}
@@ -1,9 +1,12 @@
 //! # Poll data representation
 //! This module describes the data structures for poll data.
 
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDateTime;
 pub use simple_error::SimpleError;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 pub use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
 /// Represents a valid poll response.
@@ -61,29 +64,118 @@ impl FromStr for Response {
     }
 }
 
-/// Marker type for string representation of slots
-pub type Slot = String;
+/// A time slot of a poll.
+///
+/// Importers fill in the times as far as the poll format allows,
+/// so code relying on them has to cope with slots that only have a label.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    /// The slot as it is called in the poll, e.g. "2019-02-24 Spät"
+    pub label: String,
+    /// When the slot starts, if the importer could tell
+    pub start: Option<NaiveDateTime>,
+    /// When the slot ends, if the poll says so
+    pub end: Option<NaiveDateTime>,
+}
+
+impl Slot {
+    /// Create a slot without known times
+    pub fn new(label: &str) -> Slot {
+        Slot {
+            label: label.to_owned(),
+            start: None,
+            end: None,
+        }
+    }
+
+    /// The length of the slot in hours, if its start and end are known
+    pub fn hours(&self) -> Option<f32> {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) => Some((end - start).num_minutes() as f32 / 60.0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+/// How slots are displayed in schedules and csv exports.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum SlotFormat {
+    /// The label from the poll
+    #[default]
+    Label,
+    /// A `chrono` format string for the start time, e.g. "%a %d.%m. %H:%M".
+    ///
+    /// Slots without a start time fall back to their label.
+    Pattern(String),
+}
+
+impl SlotFormat {
+    pub fn format(&self, slot: &Slot) -> String {
+        match (self, slot.start) {
+            (SlotFormat::Pattern(pattern), Some(start)) => start.format(pattern).to_string(),
+            _ => slot.label.clone(),
+        }
+    }
+}
+
+impl FromStr for SlotFormat {
+    type Err = SimpleError;
+
+    /// Parses "label" or a `chrono` format string
+    fn from_str(s: &str) -> Result<SlotFormat, SimpleError> {
+        if s.eq_ignore_ascii_case("label") {
+            return Ok(SlotFormat::Label);
+        }
+        if StrftimeItems::new(s).any(|item| item == Item::Error) {
+            return Err(SimpleError::new(format!("Invalid slot format: '{}'", s)));
+        }
+        Ok(SlotFormat::Pattern(s.to_owned()))
+    }
+}
+
 /// Marker type for string representation of names
 pub type Name = String;
+/// Marker type for string representation of roles, e.g. "keyholder"
+pub type Role = String;
 pub type PollData = Vec<PollColumn>;
 
 /// Represents the poll data for one slot.
 ///
-/// It contains everyone's responses for one time slot in a `HashMap` indexed by name.
+/// It contains everyone's responses for one time slot in a `BTreeMap` indexed by name,
+/// so that they are always iterated in the same order.
 #[derive(Debug, Clone)]
 pub struct PollColumn {
     pub time: Slot,
-    pub responses: HashMap<Name, Response>,
+    pub responses: BTreeMap<Name, Response>,
+    /// How many people are needed for this slot.
+    ///
+    /// If this is `None`, `SchedulingOptions::headcount` is used.
+    pub headcount: Option<usize>,
+    /// How much this slot counts towards the workload of each person assigned to it.
+    ///
+    /// If this is `None`, `SchedulingOptions::load` decides.
+    pub weight: Option<f32>,
+    /// Roles of which at least one assigned person must have each, see `SchedulingOptions::roles`
+    pub roles: Vec<Role>,
 }
 
 impl PollColumn {
     /// Create a new poll column for the given time.
     ///
-    /// The responses hashmap is initialized as well.
-    pub fn new(time: &str) -> PollColumn {
+    /// The responses map is initialized as well.
+    pub fn new(time: Slot) -> PollColumn {
         PollColumn {
-            time: time.to_owned(),
-            responses: HashMap::new(),
+            time,
+            responses: BTreeMap::new(),
+            headcount: None,
+            weight: None,
+            roles: Vec::new(),
         }
     }
 }
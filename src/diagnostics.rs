@@ -0,0 +1,295 @@
+//! # Diagnostics
+//! Explanations for polls without a valid schedule.
+//!
+//! Filling the slots is modelled as a flow from the slots to the people available for them,
+//! where everyone takes at most their maximum number of shifts. If the maximum flow falls short
+//! of the people needed, the minimum cut yields a set of slots that cannot all be filled,
+//! which is then shrunk as long as the remaining slots still conflict.
+//! Rests, roles, pairings and minimum numbers of shifts are not modelled,
+//! so a poll may be impossible without a conflict being found.
+
+use crate::data::*;
+use crate::scheduling::{SchedulingOptions, SearchContext};
+use std::collections::VecDeque;
+
+/// Why a poll has no valid schedule
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnosis {
+    /// Slots where fewer people answered Yes or IfNeedBe than are needed, one at a time
+    pub understaffed: Vec<Conflict>,
+    /// A minimal set of the other slots that cannot all be filled within the shift limits
+    pub conflict: Option<Conflict>,
+    /// A minimal set of the other slots that can only be filled if someone takes more shifts
+    /// than the exhaustive search assigns anyone. The other solvers have no such cap.
+    pub capped: Option<Conflict>,
+}
+
+/// Slots that need more people than are available for them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub slots: Vec<usize>,
+    /// The number of people these slots need
+    pub needed: usize,
+    /// Everyone available for any of these slots, with the number of them they can take at most
+    pub people: Vec<(Name, usize)>,
+}
+
+impl Conflict {
+    /// The number of places in these slots that can be filled at most
+    pub fn available(&self) -> usize {
+        self.people.iter().map(|&(_, count)| count).sum()
+    }
+}
+
+impl Diagnosis {
+    /// Whether anything explaining the failure was found
+    pub fn is_empty(&self) -> bool {
+        self.understaffed.is_empty() && self.conflict.is_none() && self.capped.is_none()
+    }
+
+    /// Human-readable explanations, one per line
+    pub fn describe(&self, data: &[PollColumn], format: &SlotFormat) -> Vec<String> {
+        let slot_name = |slot: usize| format!("{} ({})", slot + 1, format.format(&data[slot].time));
+        let slot_names = |slots: &[usize]| {
+            slots
+                .iter()
+                .map(|&slot| slot_name(slot))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let people = |conflict: &Conflict| {
+            conflict
+                .people
+                .iter()
+                .map(|(name, count)| format!("{} ({})", name, count))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        let mut lines = Vec::new();
+        for conflict in &self.understaffed {
+            let slot = slot_name(conflict.slots[0]);
+            if conflict.people.is_empty() {
+                lines.push(format!("Nobody answered Yes or IfNeedBe for slot {}", slot));
+            } else {
+                lines.push(format!(
+                    "Slot {} needs {} people, but only {} answered Yes or IfNeedBe",
+                    slot,
+                    conflict.needed,
+                    people(conflict)
+                ));
+            }
+        }
+        if let Some(conflict) = &self.conflict {
+            lines.push(format!(
+                "Slots {} need {} people, but at most {} of these shifts can be taken \
+                 within the shift limits: {}",
+                slot_names(&conflict.slots),
+                conflict.needed,
+                conflict.available(),
+                people(conflict)
+            ));
+        }
+        if let Some(conflict) = &self.capped {
+            lines.push(format!(
+                "Slots {} need {} people, but the exhaustive search assigns these people \
+                 at most {} of these shifts: {}. Try --solver ilp",
+                slot_names(&conflict.slots),
+                conflict.needed,
+                conflict.available(),
+                people(conflict)
+            ));
+        }
+        if lines.is_empty() {
+            lines.push(String::from(
+                "No conflict between the responses and the shift limits was found, \
+                 so the minimum shifts, rests, roles or pairings are too strict",
+            ));
+        }
+        lines
+    }
+}
+
+/// Explain why there is no valid schedule for the poll.
+///
+/// With `SchedulingOptions::ignore_empty_slots`, placeholders fill whatever cannot be filled otherwise,
+/// so only the constraints that are not modelled can conflict.
+pub fn diagnose(data: &[PollColumn], opts: &SchedulingOptions) -> Diagnosis {
+    if data.is_empty() || opts.ignore_empty_slots {
+        return Diagnosis::default();
+    }
+    let pinned = opts.pinned_names(data);
+    let available = data
+        .iter()
+        .zip(&pinned)
+        .map(|(day, pinned)| {
+            day.responses
+                .iter()
+                .filter(|(person, response)| {
+                    !matches!(response, Response::No) || pinned.contains(&person.as_str())
+                })
+                .map(|(person, _)| person.as_str())
+                .collect::<Vec<&str>>()
+        })
+        .collect::<Vec<Vec<&str>>>();
+
+    let mut understaffed = Vec::new();
+    let mut others = Vec::new();
+    for (slot, (day, available)) in data.iter().zip(&available).enumerate() {
+        if available.len() < opts.headcount_of(day) {
+            understaffed.push(Conflict {
+                slots: vec![slot],
+                needed: opts.headcount_of(day),
+                people: available.iter().map(|&name| (name.to_owned(), 1)).collect(),
+            });
+        } else {
+            others.push(slot);
+        }
+    }
+
+    let people = data[0]
+        .responses
+        .keys()
+        .map(|person| person.as_str())
+        .collect::<Vec<&str>>();
+    let limits = people
+        .iter()
+        .map(|person| match opts.limits.get(*person) {
+            Some(limits) => limits.max.unwrap_or(data.len()),
+            None => data.len(),
+        })
+        .collect::<Vec<usize>>();
+    let ctx = SearchContext::new(data, opts);
+    let caps = people
+        .iter()
+        .map(|person| ctx.cap(person))
+        .collect::<Vec<usize>>();
+
+    let network = Network {
+        needed: data.iter().map(|day| opts.headcount_of(day)).collect(),
+        available: available
+            .iter()
+            .map(|names| {
+                names
+                    .iter()
+                    .map(|name| people.iter().position(|p| p == name).unwrap())
+                    .collect()
+            })
+            .collect(),
+        people,
+    };
+    let conflict = network.conflict(&others, &limits);
+    let capped = match conflict {
+        Some(_) => None,
+        None => network.conflict(&others, &caps),
+    };
+    Diagnosis {
+        understaffed,
+        conflict,
+        capped,
+    }
+}
+
+/// The bipartite network of slots and people
+struct Network<'data> {
+    /// The number of people each slot needs
+    needed: Vec<usize>,
+    /// For each slot, the indices of the people available for it
+    available: Vec<Vec<usize>>,
+    people: Vec<&'data str>,
+}
+
+impl<'data> Network<'data> {
+    /// A minimal set of the given slots that cannot all be filled, if there is one
+    fn conflict(&self, slots: &[usize], caps: &[usize]) -> Option<Conflict> {
+        let (flow, mut conflict) = self.max_flow(slots, caps);
+        if flow == self.needed(slots) {
+            return None;
+        }
+        // Drop slots as long as the remaining ones still cannot be filled
+        let mut i = 0;
+        while i < conflict.len() {
+            let mut rest = conflict.clone();
+            rest.remove(i);
+            if self.max_flow(&rest, caps).0 < self.needed(&rest) {
+                conflict = rest;
+            } else {
+                i += 1;
+            }
+        }
+
+        let people = (0..self.people.len())
+            .filter_map(|person| {
+                let count = conflict
+                    .iter()
+                    .filter(|&&slot| self.available[slot].contains(&person))
+                    .count();
+                match count.min(caps[person]) {
+                    0 => None,
+                    count => Some((self.people[person].to_owned(), count)),
+                }
+            })
+            .collect();
+        Some(Conflict {
+            needed: self.needed(&conflict),
+            slots: conflict,
+            people,
+        })
+    }
+
+    fn needed(&self, slots: &[usize]) -> usize {
+        slots.iter().map(|&slot| self.needed[slot]).sum()
+    }
+
+    /// The maximum number of places in the given slots that can be filled,
+    /// and those of the slots on the source side of a minimum cut
+    fn max_flow(&self, slots: &[usize], caps: &[usize]) -> (usize, Vec<usize>) {
+        // Nodes: the source, the slots, the people and the sink
+        let source = 0;
+        let sink = 1 + slots.len() + self.people.len();
+        let person_node = |person: usize| 1 + slots.len() + person;
+        let mut residual = vec![vec![0; sink + 1]; sink + 1];
+        for (i, &slot) in slots.iter().enumerate() {
+            residual[source][1 + i] = self.needed[slot];
+            for &person in &self.available[slot] {
+                residual[1 + i][person_node(person)] = 1;
+            }
+        }
+        for (person, &cap) in caps.iter().enumerate() {
+            residual[person_node(person)][sink] = cap;
+        }
+
+        // Augment along shortest paths until the sink cannot be reached anymore
+        let mut flow = 0;
+        loop {
+            let mut previous = vec![None; sink + 1];
+            previous[source] = Some(source);
+            let mut queue = VecDeque::from(vec![source]);
+            while let Some(node) = queue.pop_front() {
+                for next in 0..=sink {
+                    if previous[next].is_none() && residual[node][next] > 0 {
+                        previous[next] = Some(node);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            if previous[sink].is_none() {
+                let cut = slots
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| previous[1 + i].is_some())
+                    .map(|(_, &slot)| slot)
+                    .collect();
+                return (flow, cut);
+            }
+            let mut node = sink;
+            while node != source {
+                let prev = previous[node].unwrap();
+                residual[prev][node] -= 1;
+                residual[node][prev] += 1;
+                node = prev;
+            }
+            flow += 1;
+        }
+    }
+}
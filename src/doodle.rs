@@ -2,6 +2,7 @@
 //! This module can load data exported from [Doodle](https://doodle.com).
 
 use crate::data::*;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
 use csv::Reader;
 
 /// Reads data formatted like that from Doodle.
@@ -16,15 +17,20 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
         match time {
             // Only new days are included in the csv...
             "" => data.push(data[data.len() - 1].clone()),
-            _ => data.push(PollColumn::new(time)),
+            _ => data.push(PollColumn::new(Slot::new(time))),
         }
     }
     for r in rdr.records() {
         let r = r?;
         // Add day and time if they exist - checking for the absence of a name
         if &r[0] == "" {
+            // Days are only included once as well
+            let mut previous = "";
             for (time, poll_column) in r.iter().skip(1).zip(&mut data) {
-                poll_column.time += &format!(" {}", time);
+                if !time.is_empty() {
+                    previous = time;
+                }
+                poll_column.time.label += &format!(" {}", previous);
             }
             continue;
         }
@@ -41,5 +47,51 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
         }
     }
 
+    for poll_column in &mut data {
+        let (start, end) = parse_times(&poll_column.time.label);
+        poll_column.time.start = start;
+        poll_column.time.end = end;
+    }
+
     Ok(data)
 }
+
+/// Parse the times from labels like "March 2019 Mon 4 10:00 AM 11:00 AM".
+///
+/// Slots without a time of day start at midnight.
+fn parse_times(label: &str) -> (Option<NaiveDateTime>, Option<NaiveDateTime>) {
+    let tokens: Vec<&str> = label.split_whitespace().collect();
+    if tokens.len() < 4 {
+        return (None, None);
+    }
+    let date = format!("{} {} {}", tokens[3], tokens[0], tokens[1]);
+    let date = match NaiveDate::parse_from_str(&date, "%d %B %Y") {
+        Ok(date) => date,
+        Err(_) => return (None, None),
+    };
+
+    let mut times = Vec::new();
+    for (i, token) in tokens.iter().enumerate().skip(4) {
+        let twelve_hour = tokens
+            .get(i + 1)
+            .filter(|next| next.eq_ignore_ascii_case("AM") || next.eq_ignore_ascii_case("PM"))
+            .map(|next| NaiveTime::parse_from_str(&format!("{} {}", token, next), "%I:%M %p"));
+        match twelve_hour.unwrap_or_else(|| NaiveTime::parse_from_str(token, "%H:%M")) {
+            Ok(time) => times.push(time),
+            Err(_) => continue,
+        }
+    }
+
+    let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    let start = date.and_time(times.first().copied().unwrap_or(midnight));
+    let end = times.get(1).map(|&time| {
+        let end = date.and_time(time);
+        // Slots ending after midnight
+        if end < start {
+            end + Duration::days(1)
+        } else {
+            end
+        }
+    });
+    (Some(start), end)
+}
@@ -2,6 +2,7 @@
 //! This module can load data exported from [Framadate](https://framadate.org/).
 
 use crate::data::*;
+use chrono::{NaiveDate, NaiveTime};
 use csv::Reader;
 use scan_fmt::scan_fmt;
 
@@ -14,12 +15,11 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
 
     for time in rdr.headers()? {
         if time != "" {
-            let (month, day) = scan_fmt!(time, "{*d}-{d}-{d}", i32, i32)?;
-            data.push(PollColumn::new(&format!(
-                "{:02}.{:02}.",
-                day,
-                month
-            )));
+            let (year, month, day) = scan_fmt!(time, "{d}-{d}-{d}", i32, u32, u32)?;
+            let mut slot = Slot::new(time);
+            slot.start = NaiveDate::from_ymd_opt(year, month, day)
+                .and_then(|date| date.and_hms_opt(0, 0, 0));
+            data.push(PollColumn::new(slot));
         }
     }
     for (i, r) in rdr.records().enumerate() {
@@ -27,7 +27,14 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
         if i == 0 {
             // Skip empty name, ignore trailing empty column
             for (time, poll_column) in r?.iter().skip(1).take(data.len()).zip(&mut data) {
-                poll_column.time += &format!(" {}", time);
+                let slot = &mut poll_column.time;
+                slot.label += &format!(" {}", time);
+                // Times may also be labels like "evening", which leave the start at midnight
+                if let (Some(start), Ok(parsed)) =
+                    (slot.start, NaiveTime::parse_from_str(time, "%H:%M"))
+                {
+                    slot.start = Some(start.date().and_time(parsed));
+                }
             }
             continue;
         }
@@ -0,0 +1,605 @@
+//! # Integer programming
+//! An exact solver that formulates the scheduling problem as an integer program.
+//!
+//! The built-in terms of the cost function are modelled exactly, with the squares and the
+//! average distances linearized using additional variables, and minimized by branch and bound
+//! over the LP relaxation, which is solved by the pure-Rust [minilp](https://docs.rs/minilp) solver.
+//! Other terms are not modelled, but they are non-negative, so solutions are enumerated in order of
+//! their modelled cost until that exceeds the full cost of the best schedules found, which proves them optimal.
+//!
+//! This needs `SchedulingOptions::cost_terms` to contain the built-in terms, which `check_constraints` ensures.
+//! Unlike the exhaustive search, no one's number of shifts is capped (see `SearchContext::max_occur`),
+//! so where that cap excludes the best schedules, both solvers return different ones.
+//! Placeholders are only used where too few people are available (see `SchedulingOptions::placeholders_allowed`),
+//! while the exhaustive search also uses them for people it leaves out because of that cap or a rest.
+
+use crate::constraints::{Pairing, PairingKind};
+use crate::data::*;
+use crate::progress::Tracker;
+use crate::scheduling::{
+    evaluate, BestSchedules, Diversity, NameOrder, ScheduleEntry, SchedulingOptions,
+};
+use minilp::{ComparisonOp, OptimizationDirection, Problem, Solution, Variable};
+use std::time::Instant;
+
+/// Values closer than this to an integer are considered integral
+const INTEGRALITY_TOLERANCE: f64 = 1e-6;
+
+/// Slack for rounding errors when comparing modelled and full costs
+const COST_TOLERANCE: f64 = 1e-4;
+
+/// The integer program for a poll
+struct Model<'data> {
+    problem: Problem,
+    /// Which person each variable assigns to which slot.
+    ///
+    /// Placeholder variables count the `??` in a slot, all others are binary.
+    assignments: Vec<(usize, &'data str, Variable)>,
+    /// All variables that need to be integral, including the assignments
+    integers: Vec<Variable>,
+    /// Added to the objective: the cost of changing every previous assignment,
+    /// which keeping one reduces
+    offset: f64,
+}
+
+impl<'data> Model<'data> {
+    fn new(data: &'data [PollColumn], opts: &SchedulingOptions) -> Model<'data> {
+        let mut problem = Problem::new(OptimizationDirection::Minimize);
+        let mut assignments = Vec::new();
+        let mut integers = Vec::new();
+        let offset = opts.weights.change as f64 * opts.previous.len() as f64;
+
+        for (slot, day) in data.iter().enumerate() {
+            let mut names = day
+                .responses
+                .iter()
+                .filter(|(_, response)| !matches!(response, Response::No))
+                .collect::<Vec<_>>();
+            names.sort_by_key(|(person, _)| person.as_str());
+
+            let mut filled = Vec::new();
+            for (person, response) in names {
+                let mut cost = match response {
+                    Response::IfNeedBe => opts.weights.ifneedbe,
+                    _ => 0.0,
+                };
+                let previous = opts
+                    .previous
+                    .iter()
+                    .filter(|pin| pin.slot == slot && &pin.name == person)
+                    .count();
+                cost -= opts.weights.change * previous as f32;
+                // The square of the total workload grows by twice the history for each unit of workload
+                cost +=
+                    opts.weights.occurrences * 2.0 * opts.history_of(person) * opts.load_of(day);
+                let min = if opts.is_pinned(slot, person) {
+                    1.0
+                } else {
+                    0.0
+                };
+                let var = problem.add_var(cost as f64, (min, 1.0));
+                assignments.push((slot, person.as_str(), var));
+                filled.push((var, 1.0));
+            }
+            let headcount = opts.headcount_of(day) as f64;
+            let placeholders = opts.placeholders_allowed(day);
+            if placeholders > 0 {
+                let var =
+                    problem.add_var(opts.weights.placeholder as f64, (0.0, placeholders as f64));
+                assignments.push((slot, "??", var));
+                filled.push((var, 1.0));
+            }
+            problem.add_constraint(filled.as_slice(), ComparisonOp::Eq, headcount);
+
+            // At least one qualified person or placeholder per required role
+            for role in &day.roles {
+                let qualified = assignments
+                    .iter()
+                    .filter(|&&(s, person, _)| {
+                        s == slot && (person == "??" || opts.has_role(person, role))
+                    })
+                    .map(|&(_, _, var)| (var, 1.0))
+                    .collect::<Vec<(Variable, f64)>>();
+                problem.add_constraint(qualified.as_slice(), ComparisonOp::Ge, 1.0);
+            }
+
+            for pairing in &opts.pairings {
+                let var_of = |name: &str| {
+                    assignments
+                        .iter()
+                        .find(|&&(s, person, _)| s == slot && person == name)
+                        .map(|&(_, _, var)| var)
+                };
+                add_pairing(
+                    &mut problem,
+                    pairing,
+                    var_of(&pairing.first),
+                    var_of(&pairing.second),
+                );
+            }
+        }
+
+        // Equal positions would hide distances from the cost, so the model would overestimate it
+        let positions = opts
+            .slot_positions(data)
+            .into_iter()
+            .map(f64::from)
+            .collect::<Vec<f64>>();
+        let model_distance =
+            opts.weights.distance > 0.0 && positions.windows(2).all(|pair| pair[0] < pair[1]);
+        let load_step = load_step(data, opts);
+
+        let mut people = assignments
+            .iter()
+            .map(|&(_, person, _)| person)
+            .collect::<Vec<&str>>();
+        people.sort_unstable();
+        people.dedup();
+        for person in people {
+            let shifts = assignments
+                .iter()
+                .filter(|&&(_, name, _)| name == person)
+                .map(|&(slot, _, var)| (slot, var))
+                .collect::<Vec<(usize, Variable)>>();
+            let count = shifts
+                .iter()
+                .map(|&(_, var)| (var, 1.0))
+                .collect::<Vec<(Variable, f64)>>();
+            add_load(&mut problem, &shifts, person, data, load_step, opts);
+
+            // At most one shift within every window of `min_rest + 1` consecutive slots
+            if person != "??" && opts.min_rest > 0 {
+                for start in 0..data.len() {
+                    let window = shifts
+                        .iter()
+                        .filter(|&&(slot, _)| slot >= start && slot <= start + opts.min_rest)
+                        .map(|&(_, var)| (var, 1.0))
+                        .collect::<Vec<(Variable, f64)>>();
+                    if window.len() > 1 {
+                        problem.add_constraint(window.as_slice(), ComparisonOp::Le, 1.0);
+                    }
+                }
+            }
+
+            if let Some(limits) = opts.limits.get(person) {
+                problem.add_constraint(count.as_slice(), ComparisonOp::Ge, limits.min as f64);
+                if let Some(max) = limits.max {
+                    problem.add_constraint(count.as_slice(), ComparisonOp::Le, max as f64);
+                }
+            }
+
+            // Placeholders can appear several times per slot, which the distance does not count,
+            // so they are left to the enumeration
+            if person != "??" && model_distance {
+                add_distance(&mut problem, &mut integers, &shifts, &positions, opts);
+            }
+        }
+
+        integers.extend(assignments.iter().map(|&(_, _, var)| var));
+        Model {
+            problem,
+            assignments,
+            integers,
+            offset,
+        }
+    }
+
+    /// The modelled cost of a solution
+    fn cost(&self, solution: &Solution) -> f64 {
+        solution.objective() + self.offset
+    }
+
+    /// The `limit` best integral solutions, and whether the search for them was complete
+    fn solve(&self, limit: usize, stopped: impl Fn() -> bool) -> (Vec<Solution>, bool) {
+        let mut best: Vec<Solution> = Vec::new();
+        if limit == 0 {
+            return (best, true);
+        }
+        let mut stack = self.problem.solve().into_iter().collect::<Vec<Solution>>();
+
+        while let Some(solution) = stack.pop() {
+            if stopped() {
+                return (best, false);
+            }
+            if best.len() == limit
+                && solution.objective() >= best[limit - 1].objective() - COST_TOLERANCE
+            {
+                continue;
+            }
+            let fractional = self.integers.iter().copied().find(|&var| {
+                let value = solution[var];
+                (value - value.round()).abs() > INTEGRALITY_TOLERANCE
+            });
+            match fractional {
+                None => {
+                    // Other solutions may still hide below this one
+                    let (cut, rhs) = self.exclusion(&solution);
+                    stack.extend(
+                        solution
+                            .clone()
+                            .add_constraint(cut.as_slice(), ComparisonOp::Le, rhs)
+                            .ok(),
+                    );
+                    let position = best
+                        .iter()
+                        .position(|other| other.objective() > solution.objective())
+                        .unwrap_or(best.len());
+                    best.insert(position, solution);
+                    best.truncate(limit);
+                }
+                Some(var) => {
+                    let value = solution[var];
+                    let down = solution.clone().add_constraint(
+                        [(var, 1.0)],
+                        ComparisonOp::Le,
+                        value.floor(),
+                    );
+                    let up = solution.add_constraint([(var, 1.0)], ComparisonOp::Ge, value.ceil());
+                    // Explore the branch closer to the relaxed value first
+                    let (first, second) = if value - value.floor() < 0.5 {
+                        (down, up)
+                    } else {
+                        (up, down)
+                    };
+                    stack.extend(second.ok());
+                    stack.extend(first.ok());
+                }
+            }
+        }
+
+        (best, true)
+    }
+
+    /// Turn a solution into a schedule
+    fn schedule<'b>(
+        &self,
+        data: &'data [PollColumn],
+        solution: &Solution,
+    ) -> Vec<ScheduleEntry<'data, 'b>>
+    where
+        'data: 'b,
+    {
+        data.iter()
+            .enumerate()
+            .map(|(i, day)| {
+                let mut names = Vec::new();
+                for &(slot, person, var) in &self.assignments {
+                    if slot == i {
+                        let count = solution[var].round() as usize;
+                        names.extend(std::iter::repeat_n(person, count));
+                    }
+                }
+                ScheduleEntry::new(&day.time, &names)
+            })
+            .collect()
+    }
+
+    /// A constraint that is violated by exactly the assignments of this solution
+    fn exclusion(&self, solution: &Solution) -> (Vec<(Variable, f64)>, f64) {
+        let mut cut = Vec::new();
+        let mut assigned = 0.0;
+        for &(_, person, var) in &self.assignments {
+            // Placeholders are determined by the other assignments
+            if person == "??" {
+                continue;
+            }
+            if solution[var].round() > 0.5 {
+                cut.push((var, 1.0));
+                assigned += 1.0;
+            } else {
+                cut.push((var, -1.0));
+            }
+        }
+        (cut, assigned - 1.0)
+    }
+
+    /// Exclude the assignments of this solution from now on
+    fn exclude(&mut self, solution: &Solution) {
+        let (cut, rhs) = self.exclusion(solution);
+        self.problem
+            .add_constraint(cut.as_slice(), ComparisonOp::Le, rhs);
+    }
+}
+
+/// Model a pairing in one slot, where the variables are `None` if the person is not available.
+///
+/// A soft pairing adds a variable for the violation, which costs nothing unless it is forced to 1.
+fn add_pairing(
+    problem: &mut Problem,
+    pairing: &Pairing,
+    first: Option<Variable>,
+    second: Option<Variable>,
+) {
+    // The pairing is violated if one of these sums exceeds its threshold
+    let term = |var: Option<Variable>, coef: f64| var.map(|var| (var, coef));
+    let sums = match (pairing.kind, first, second) {
+        (PairingKind::Together, _, _) => vec![
+            (
+                term(first, 1.0)
+                    .into_iter()
+                    .chain(term(second, -1.0))
+                    .collect(),
+                0.0,
+            ),
+            (
+                term(second, 1.0)
+                    .into_iter()
+                    .chain(term(first, -1.0))
+                    .collect(),
+                0.0,
+            ),
+        ],
+        (PairingKind::Apart, Some(first), Some(second)) => {
+            vec![(vec![(first, 1.0), (second, 1.0)], 1.0)]
+        }
+        (PairingKind::Apart, _, _) => Vec::new(),
+    };
+
+    let mut violated = None;
+    for (mut sum, threshold) in sums {
+        if sum.is_empty() {
+            continue;
+        }
+        if let Some(cost) = pairing.cost {
+            let var = *violated.get_or_insert_with(|| problem.add_var(cost as f64, (0.0, 1.0)));
+            sum.push((var, -1.0));
+        }
+        problem.add_constraint(sum.as_slice(), ComparisonOp::Le, threshold);
+    }
+}
+
+/// Model one person's squared workload, which is their number of shifts unless slots are weighted.
+///
+/// If the workloads of all slots are multiples of `step`, the square is the sum of the first odd multiples
+/// of `step²`, which is exact. Otherwise, it is bounded from below by its tangents at evenly spaced points,
+/// which underestimates the workloads in between.
+fn add_load(
+    problem: &mut Problem,
+    shifts: &[(usize, Variable)],
+    person: &str,
+    data: &[PollColumn],
+    step: Option<f64>,
+    opts: &SchedulingOptions,
+) {
+    let weight = opts.weights.occurrences as f64;
+    let loads = shifts
+        .iter()
+        .map(|&(slot, var)| (var, opts.load_of(&data[slot]) as f64))
+        .collect::<Vec<(Variable, f64)>>();
+    let max_load: f64 = shifts
+        .iter()
+        .map(|&(slot, _)| {
+            let places = match person {
+                "??" => opts.headcount_of(&data[slot]),
+                _ => 1,
+            };
+            opts.load_of(&data[slot]) as f64 * places as f64
+        })
+        .sum();
+    if max_load <= 0.0 {
+        return;
+    }
+
+    match step.filter(|&step| max_load / step <= MAX_SEGMENTS as f64) {
+        Some(step) => {
+            let mut squares = loads
+                .iter()
+                .map(|&(var, load)| (var, load / step))
+                .collect::<Vec<(Variable, f64)>>();
+            for j in 1..=(max_load / step).round() as usize {
+                let cost = weight * (2 * j - 1) as f64 * step * step;
+                squares.push((problem.add_var(cost, (0.0, 1.0)), -1.0));
+            }
+            problem.add_constraint(squares.as_slice(), ComparisonOp::Eq, 0.0);
+        }
+        None => {
+            // The cost is weighted in the objective, and at least 2a load - a² for every point a
+            let cost = problem.add_var(weight, (0.0, f64::INFINITY));
+            for j in 1..=MAX_SEGMENTS {
+                let a = max_load * j as f64 / MAX_SEGMENTS as f64;
+                let mut tangent = loads
+                    .iter()
+                    .map(|&(var, load)| (var, -2.0 * a * load))
+                    .collect::<Vec<(Variable, f64)>>();
+                tangent.push((cost, 1.0));
+                problem.add_constraint(tangent.as_slice(), ComparisonOp::Ge, -a * a);
+            }
+        }
+    }
+}
+
+/// The largest workload that the workload of every slot is a multiple of, if any
+fn load_step(data: &[PollColumn], opts: &SchedulingOptions) -> Option<f64> {
+    let loads = data
+        .iter()
+        .map(|day| opts.load_of(day) as f64)
+        .collect::<Vec<f64>>();
+    let step = loads.iter().fold(0.0, |step, &load| gcd(step, load));
+    let multiple = |load: f64| ((load / step) - (load / step).round()).abs() < LOAD_TOLERANCE;
+    if step > 0.0 && loads.iter().all(|&load| multiple(load)) {
+        Some(step)
+    } else {
+        None
+    }
+}
+
+/// The greatest common divisor of two non-negative numbers, up to rounding errors
+fn gcd(a: f64, b: f64) -> f64 {
+    if b < LOAD_TOLERANCE {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Slack for rounding errors of workloads
+const LOAD_TOLERANCE: f64 = 1e-4;
+
+/// At most this many variables or tangents are used to model a squared workload
+const MAX_SEGMENTS: usize = 1000;
+
+/// Model one person's distance cost, where `positions` locates each slot.
+///
+/// The average distance between `count` shifts is `span / (count - 1)`,
+/// where the span is the distance between the first and the last shift,
+/// so the cost is `(count - 1)² / span²`.
+/// A binary variable selects the span, and a copy of the count that is zero unless its span is selected
+/// bounds the cost from below by the tangents of that function (a perspective formulation).
+fn add_distance(
+    problem: &mut Problem,
+    integers: &mut Vec<Variable>,
+    shifts: &[(usize, Variable)],
+    positions: &[f64],
+    opts: &SchedulingOptions,
+) {
+    let weight = opts.weights.distance as f64;
+    let max_count = shifts.len();
+    if max_count < 2 {
+        return;
+    }
+
+    // Every distance between two of the slots is a possible span
+    let mut distances = Vec::new();
+    for (i, &(slot, _)) in shifts.iter().enumerate() {
+        for &(other, _) in &shifts[i + 1..] {
+            distances.push((positions[other] - positions[slot]).abs());
+        }
+    }
+    distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    distances.dedup_by(|a, b| (*a - *b).abs() < INTEGRALITY_TOLERANCE);
+
+    // span = last - first, where both are slots this person is assigned to
+    let mut span = Vec::new();
+    let mut first_selected = Vec::new();
+    let mut last_selected = Vec::new();
+    for &(slot, assigned) in shifts {
+        let first = problem.add_var(0.0, (0.0, 1.0));
+        let last = problem.add_var(0.0, (0.0, 1.0));
+        integers.push(first);
+        integers.push(last);
+        problem.add_constraint([(first, 1.0), (assigned, -1.0)], ComparisonOp::Le, 0.0);
+        problem.add_constraint([(last, 1.0), (assigned, -1.0)], ComparisonOp::Le, 0.0);
+        span.push((last, positions[slot]));
+        span.push((first, -positions[slot]));
+        first_selected.push((first, 1.0));
+        last_selected.push((last, 1.0));
+    }
+
+    // count = single + sum of the per-span counts, where single is at most one shift without a span
+    let single = problem.add_var(0.0, (0.0, 1.0));
+    let mut split_count = shifts
+        .iter()
+        .map(|&(_, var)| (var, 1.0))
+        .collect::<Vec<(Variable, f64)>>();
+    split_count.push((single, -1.0));
+    let mut span_selected = Vec::new();
+
+    for distance in distances {
+        let selected = problem.add_var(0.0, (0.0, 1.0));
+        let count = problem.add_var(0.0, (0.0, max_count as f64));
+        let cost = problem.add_var(weight, (0.0, f64::INFINITY));
+        integers.push(selected);
+        span.push((selected, -distance));
+        span_selected.push((selected, 1.0));
+        split_count.push((count, -1.0));
+
+        // 2 <= count <= max_count if selected, 0 otherwise
+        problem.add_constraint(
+            [(count, 1.0), (selected, -(max_count as f64))],
+            ComparisonOp::Le,
+            0.0,
+        );
+        problem.add_constraint([(count, 1.0), (selected, -2.0)], ComparisonOp::Ge, 0.0);
+
+        // cost >= (2k (count - selected) - k² selected) / d² for every integer k
+        let d2 = distance * distance;
+        for k in 1..max_count {
+            let k = k as f64;
+            problem.add_constraint(
+                [
+                    (cost, 1.0),
+                    (count, -2.0 * k / d2),
+                    (selected, (2.0 * k + k * k) / d2),
+                ],
+                ComparisonOp::Ge,
+                0.0,
+            );
+        }
+    }
+    problem.add_constraint(split_count.as_slice(), ComparisonOp::Eq, 0.0);
+    problem.add_constraint(span.as_slice(), ComparisonOp::Eq, 0.0);
+
+    // Either a single shift or exactly one span, and the first and last shift are selected exactly if a span is
+    let mut one_of = span_selected.clone();
+    one_of.push((single, 1.0));
+    problem.add_constraint(one_of.as_slice(), ComparisonOp::Le, 1.0);
+    for selected in &[first_selected, last_selected] {
+        let mut same = selected.clone();
+        same.extend(span_selected.iter().map(|&(var, _)| (var, -1.0)));
+        problem.add_constraint(same.as_slice(), ComparisonOp::Eq, 0.0);
+    }
+}
+
+/// Find the optimal schedules by solving a sequence of integer programs
+pub fn compute_schedules<'data, 'b>(
+    data: &'data [PollColumn],
+    opts: &SchedulingOptions,
+) -> BestSchedules<'data, 'b>
+where
+    'data: 'b,
+{
+    let tracker = Tracker::new(opts.observer.as_deref(), 0);
+    let best = search(data, opts, &tracker);
+    tracker.finish();
+    best
+}
+
+fn search<'data, 'b>(
+    data: &'data [PollColumn],
+    opts: &SchedulingOptions,
+    tracker: &Tracker,
+) -> BestSchedules<'data, 'b>
+where
+    'data: 'b,
+{
+    let mut model = Model::new(data, opts);
+    let order = NameOrder::new(data, opts);
+    let mut best = BestSchedules::new(opts.top, opts.diversity);
+    let start = Instant::now();
+
+    loop {
+        let (solutions, complete) = model.solve(opts.top, || {
+            tracker.step();
+            opts.stopped(start)
+        });
+        if !complete {
+            // The solutions found so far are valid, but may be neither the best nor in order
+            for solution in &solutions {
+                let evaluated = evaluate(model.schedule(data, solution), data, opts);
+                tracker.evaluated(Some(evaluated.cost));
+                best.add(evaluated, &order);
+            }
+            best.unproven();
+            return best;
+        }
+        let mut exact = true;
+        for solution in &solutions {
+            // The full cost is at least the modelled cost, so no later solution can be better
+            if let Some(final_cost) = best.final_cost() {
+                if model.cost(solution) >= final_cost as f64 - COST_TOLERANCE {
+                    return best;
+                }
+            }
+            let evaluated = evaluate(model.schedule(data, solution), data, opts);
+            exact &= (evaluated.cost as f64 - model.cost(solution)).abs() < COST_TOLERANCE;
+            tracker.evaluated(Some(evaluated.cost));
+            best.add(evaluated, &order);
+            model.exclude(solution);
+        }
+        // If the model captured the whole cost, branch and bound has already proven optimality,
+        // unless schedules that are too similar are skipped and later ones are needed instead
+        if (exact && opts.diversity == Diversity::None) || solutions.len() < opts.top {
+            return best;
+        }
+    }
+}
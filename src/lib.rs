@@ -1,13 +1,27 @@
 //! Library for roster scheduling based on poll responses.
 //!
-//! It provides functionality for loading (`framadate`) and representing (`data`) poll responses, as well as `scheduling` based on them.
+//! It provides functionality for loading (`framadate`) and representing (`data`) poll responses, additional `constraints`, as well as `scheduling` based on them using a `cost` function, `diagnostics` for polls without a valid schedule and an `analysis` of the search space.
 
 pub mod data;
 
+pub mod constraints;
+
 pub mod framadate;
 
 pub mod doodle;
 
 pub mod nextcloud;
 
+pub mod cost;
+
 pub mod scheduling;
+
+pub mod annealing;
+
+pub mod ilp;
+
+pub mod diagnostics;
+
+pub mod analysis;
+
+pub mod progress;
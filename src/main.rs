@@ -1,8 +1,11 @@
 use clap::{arg_enum, clap_app, crate_version, value_t};
+use framaschedule::constraints::{Pairing, PairingKind};
 use framaschedule::data::*;
+use framaschedule::progress::{Observer, Progress};
 use framaschedule::scheduling;
-use framaschedule::scheduling::{BestSchedules, SchedulingOptions};
-use framaschedule::{doodle, framadate, nextcloud};
+use framaschedule::scheduling::{Diversity, Load, SchedulingOptions, Solver, Spacing};
+use framaschedule::{analysis, constraints, diagnostics, doodle, framadate, nextcloud};
+use std::time::Duration;
 
 arg_enum! {
     #[derive(PartialEq, Debug)]
@@ -19,42 +22,256 @@ fn main() -> Result<(), Box<dyn Error>> {
     (author: "Bennett Piater <bennett@piater.name>")
     (about: "Automatically find the best schedule fulfilling poll responses")
     (@arg csv: --("export-csv") [output] "Output the best schedule in csv format")
+    (@arg costs: --("export-costs") [output] "Output what each cost term and person contributes to the cost of the best schedule in csv format")
+    (@arg explain: --explain "Print the cost each person causes, by term")
+    (@arg progress: --progress "Show the progress of the search")
+    (@arg slot_format: --("slot-format") [format] "How slots are printed, e.g. '%a %d.%m. %H:%M' (default: label, as in the poll)")
     (@arg ignore_empty: -F --("force-if-empty") "Ignore slots that cannot be filled")
     (@arg format: -f --format <format> +case_insensitive "The format of the input file - framadate, nextcloud or doodle")
+    (@arg headcount: -n --headcount [count] "The number of people needed per slot (default: 1)")
+    (@arg slot_headcount: --("slot-headcount") [slot_count] ... number_of_values(1) "The number of people needed for one slot, e.g. 3:2 for two people in the third slot")
+    (@arg slot_weight: --("slot-weight") [slot_weight] ... number_of_values(1) "How much one slot counts towards the workload, e.g. 3:8 for an eight-hour third slot")
+    (@arg history: --history [file] ... number_of_values(1) "A schedule exported with --export-csv or a JSON object with workload totals from earlier polls, to balance the totals")
+    (@arg roles: --roles [file] "A csv file with the roles of each person, e.g. keyholder")
+    (@arg slot_role: --("slot-role") [slot_role] ... number_of_values(1) "A role that someone in a slot must have, e.g. 3:keyholder")
+    (@arg load: --load [unit] "How each person's workload is measured - shifts (default) or hours")
+    (@arg limits: -l --limits [file] "A csv file with the minimum and maximum number of shifts per person")
+    (@arg spacing: --spacing [unit] "How distances between shifts are measured - slots (default) or days")
+    (@arg together: --together [names] ... number_of_values(1) "Two people who only take shifts together, e.g. Anna,Ben or Anna,Ben,5 to allow it at a cost of 5 per slot")
+    (@arg apart: --apart [names] ... number_of_values(1) "Two people who never take the same shift, e.g. Anna,Ben or Anna,Ben,5 to allow it at a cost of 5 per slot")
+    (@arg min_rest: --("min-rest") [slots] "The minimum number of free slots between two shifts of the same person (default: 0)")
+    (@arg pin: -p --pin [slot_name] ... number_of_values(1) "A person who must take a slot, e.g. 3:Anna for Anna in the third slot")
+    (@arg weight_occurrences: --("weight-occurrences") [weight] "Cost weight of the squared number of shifts per person (default: 1)")
+    (@arg weight_distance: --("weight-distance") [weight] "Cost weight of the inverse squared average distance between shifts (default: 1)")
+    (@arg weight_ifneedbe: --("weight-ifneedbe") [weight] "Cost of every IfNeedBe response used (default: 0.25)")
+    (@arg weight_placeholder: --("weight-placeholder") [weight] "Cost of every placeholder used (default: 5)")
+    (@arg weight_change: --("weight-change") [weight] "Cost of every changed assignment of the previous schedule (default: 10)")
+    (@arg previous: --previous [file] "A schedule exported with --export-csv to change as little as possible")
+    (@arg frozen: --frozen [count] requires[previous] "The number of leading slots of the previous schedule to keep unchanged")
+    (@arg solver: -s --solver [solver] "The algorithm to use - exhaustive (default), ilp or annealing")
+    (@arg time_limit: --("time-limit") [seconds] "How long the search may take before printing the best schedules found so far (default: no limit, 10 for annealing)")
+    (@arg threads: --threads [count] "The number of threads of the exhaustive search (default: one per physical cpu core)")
+    (@arg top: -t --top [count] "How many of the best schedules to print (default: 2)")
+    (@arg min_difference: --("min-difference") [count] conflicts_with[cost_tolerance] "Only print alternatives that differ from better schedules in at least this many assignments")
+    (@arg cost_tolerance: --("cost-tolerance") [cost] "Print the most different alternatives that cost at most this much more than the best schedule")
+    (@arg seed: --seed [seed] "Shuffle the order in which people are tried, which decides between equally good schedules")
     (@arg POLLDATA: +required "The csv file with the poll data")
+    (@subcommand analyze =>
+        (about: "Estimate the size of the search space and the time it takes instead of scheduling"))
     )
     .get_matches();
 
     let data_file = args.value_of("POLLDATA").unwrap();
-    let data = match value_t!(args.value_of("format"), Format).unwrap_or_else(|_| Format::Framadate)
-    {
-        Format::Framadate => framadate::read_data(data_file)?,
-        Format::Doodle => doodle::read_data(data_file)?,
-        Format::Nextcloud => nextcloud::read_data(data_file)?,
+    let mut data =
+        match value_t!(args.value_of("format"), Format).unwrap_or_else(|_| Format::Framadate) {
+            Format::Framadate => framadate::read_data(data_file)?,
+            Format::Doodle => doodle::read_data(data_file)?,
+            Format::Nextcloud => nextcloud::read_data(data_file)?,
+        };
+
+    for slot_count in args.values_of("slot_headcount").into_iter().flatten() {
+        let (slot, count) = parse_slot_value(slot_count, data.len(), "headcount")?;
+        data[slot].headcount = Some(count);
+    }
+    for slot_role in args.values_of("slot_role").into_iter().flatten() {
+        let (slot, role) = parse_slot_value(slot_role, data.len(), "role")?;
+        data[slot].roles.push(role);
+    }
+    for slot_weight in args.values_of("slot_weight").into_iter().flatten() {
+        let (slot, weight) = parse_slot_value(slot_weight, data.len(), "weight")?;
+        data[slot].weight = Some(weight);
+    }
+
+    let slot_format = match args.value_of("slot_format") {
+        Some(format) => format.parse()?,
+        None => SlotFormat::default(),
     };
 
-    let options = SchedulingOptions {
+    let mut options = SchedulingOptions {
         ignore_empty_slots: args.is_present("ignore_empty"),
+        ..SchedulingOptions::default()
     };
+    if args.is_present("headcount") {
+        options.headcount = value_t!(args.value_of("headcount"), usize)?;
+    }
+    if args.is_present("weight_occurrences") {
+        options.weights.occurrences = value_t!(args.value_of("weight_occurrences"), f32)?;
+    }
+    if args.is_present("weight_distance") {
+        options.weights.distance = value_t!(args.value_of("weight_distance"), f32)?;
+    }
+    if args.is_present("weight_ifneedbe") {
+        options.weights.ifneedbe = value_t!(args.value_of("weight_ifneedbe"), f32)?;
+    }
+    if args.is_present("weight_placeholder") {
+        options.weights.placeholder = value_t!(args.value_of("weight_placeholder"), f32)?;
+    }
+    if args.is_present("weight_change") {
+        options.weights.change = value_t!(args.value_of("weight_change"), f32)?;
+    }
+    if args.is_present("solver") {
+        options.solver = value_t!(args.value_of("solver"), Solver)?;
+    }
+    if args.is_present("time_limit") {
+        let seconds = value_t!(args.value_of("time_limit"), f64)?;
+        options.time_limit =
+            Some(Duration::try_from_secs_f64(seconds).map_err(|_| {
+                SimpleError::new(format!("Invalid time limit: {} seconds", seconds))
+            })?);
+    }
+    if args.is_present("threads") {
+        options.threads = Some(value_t!(args.value_of("threads"), usize)?);
+    }
+    if args.is_present("top") {
+        options.top = value_t!(args.value_of("top"), usize)?;
+    }
+    if args.is_present("min_difference") {
+        options.diversity =
+            Diversity::MinDifference(value_t!(args.value_of("min_difference"), usize)?);
+    }
+    if args.is_present("cost_tolerance") {
+        options.diversity =
+            Diversity::CostTolerance(value_t!(args.value_of("cost_tolerance"), f32)?);
+    }
+    if args.is_present("seed") {
+        options.seed = Some(value_t!(args.value_of("seed"), u64)?);
+    }
+    if let Some(limits_file) = args.value_of("limits") {
+        options.limits = constraints::read_limits(limits_file)?;
+    }
+    if let Some(roles_file) = args.value_of("roles") {
+        options.roles = constraints::read_roles(roles_file)?;
+    }
+    if args.is_present("spacing") {
+        options.spacing = value_t!(args.value_of("spacing"), Spacing)?;
+    }
+    if args.is_present("load") {
+        options.load = value_t!(args.value_of("load"), Load)?;
+    }
+    for history_file in args.values_of("history").into_iter().flatten() {
+        constraints::add_history(
+            &mut options.history,
+            constraints::read_history(history_file, options.load)?,
+        );
+    }
+    if args.is_present("min_rest") {
+        options.min_rest = value_t!(args.value_of("min_rest"), usize)?;
+    }
+    for pin in args.values_of("pin").into_iter().flatten() {
+        options.pins.push(pin.parse()?);
+    }
+    for pairing in args.values_of("together").into_iter().flatten() {
+        options
+            .pairings
+            .push(Pairing::parse(PairingKind::Together, pairing)?);
+    }
+    for pairing in args.values_of("apart").into_iter().flatten() {
+        options
+            .pairings
+            .push(Pairing::parse(PairingKind::Apart, pairing)?);
+    }
+    if let Some(previous_file) = args.value_of("previous") {
+        options.previous = constraints::read_schedule(previous_file, &data, &slot_format)?;
+        // Slots that already took place cannot change anymore
+        if args.is_present("frozen") {
+            let frozen = value_t!(args.value_of("frozen"), usize)?;
+            let past = options.previous.iter().filter(|pin| pin.slot < frozen);
+            options.pins.extend(past.cloned());
+        }
+    }
+
+    scheduling::check_constraints(&data, &options)?;
+    if args.subcommand_matches("analyze").is_some() {
+        analysis::analyze(&data, &options).print(&data, &slot_format);
+        return Ok(());
+    }
+    if args.is_present("progress") {
+        options.observer = Some(Box::new(ProgressLine));
+    }
     let result = scheduling::compute_all_schedules(&data, &options);
 
-    if args.is_present("csv") {
-        match result {
-            BestSchedules::Two(r1, _) | BestSchedules::One(r1) => {
-                r1.write_csv(args.value_of("csv").unwrap())?
-            }
-            _ => eprintln!("No valid schedule exists!"),
+    if result.is_empty() {
+        let diagnosis = diagnostics::diagnose(&data, &options);
+        if result.is_optimal() {
+            eprintln!("No valid schedule exists!");
+        } else if diagnosis.is_empty() {
+            eprintln!("No valid schedule was found in time!");
+            return Ok(());
+        } else {
+            eprintln!("No valid schedule was found in time, and there may be none!");
         }
+        for line in diagnosis.describe(&data, &slot_format) {
+            eprintln!("{}", line);
+        }
+        return Ok(());
+    }
+    if !result.is_optimal() {
+        eprintln!("The search was stopped, so better schedules may exist.");
+    }
+    if let Some(costs_file) = args.value_of("costs") {
+        result.schedules()[0].write_breakdown_csv(costs_file)?
+    }
+    if args.is_present("csv") {
+        result.schedules()[0].write_csv(args.value_of("csv").unwrap(), &slot_format)?
     } else {
-        match result {
-            BestSchedules::Two(r1, r2) => {
-                r1.print();
-                r2.print();
+        for schedule in result.schedules() {
+            schedule.print(&slot_format);
+            if args.is_present("explain") {
+                schedule.print_explanation();
             }
-            BestSchedules::One(r1) => r1.print(),
-            BestSchedules::None => eprintln!("No valid schedule exists!"),
         }
     }
 
     Ok(())
 }
+
+/// Shows the progress of the search on a single line of `stderr`
+#[derive(Debug)]
+struct ProgressLine;
+
+impl Observer for ProgressLine {
+    fn progress(&self, progress: &Progress) {
+        let mut line = format!(
+            "{:.1}s: {} schedules evaluated",
+            progress.elapsed.as_secs_f32(),
+            progress.schedules
+        );
+        if progress.branches > 0 {
+            line += &format!(
+                ", {}/{} branches done",
+                progress.branches_done, progress.branches
+            );
+        }
+        if let Some(cost) = progress.best_cost {
+            line += &format!(", best cost {}", cost);
+        }
+        // Overwrite the previous line, which may have been longer
+        eprint!("\r{:<80}", line);
+        if progress.finished {
+            eprintln!();
+        }
+    }
+}
+
+/// Parse a `slot:value` pair, where slots are numbered starting from 1
+fn parse_slot_value<T: std::str::FromStr>(
+    s: &str,
+    slots: usize,
+    what: &str,
+) -> Result<(usize, T), SimpleError> {
+    let invalid = || SimpleError::new(format!("Invalid slot {}: '{}'", what, s));
+    let mut parts = s.splitn(2, ':');
+    let slot: usize = parts.next().unwrap().parse().map_err(|_| invalid())?;
+    let value: T = parts
+        .next()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    if slot == 0 || slot > slots {
+        return Err(SimpleError::new(format!(
+            "Slot {} does not exist, there are {} slots",
+            slot, slots
+        )));
+    }
+    Ok((slot - 1, value))
+}
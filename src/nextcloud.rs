@@ -14,20 +14,25 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
     let mut rdr = Reader::from_path(file_name)?;
 
     for (i, r) in rdr.records().enumerate() {
-        // skip description and end time columns (title column is the header)
-        if i == 0 || i == 2 {
+        // skip description column (title column is the header)
+        if i == 0 {
             continue;
         }
         // Parse the from time
         if i == 1 {
             // Skip "from" column
             for time in r?.iter().skip(1) {
-                // TODO: attempt parsing the date and returning a short RFC string if possible?
-                if let Ok(parsed) = NaiveDateTime::parse_from_str(time, "%a, %b %e, %Y %l:%M %p") {
-                    data.push(PollColumn::new(&parsed.format("%Y-%m-%d").to_string()))
-                } else {
-                    data.push(PollColumn::new(time))
-                }
+                let mut slot = Slot::new(time);
+                slot.start = parse_time(time);
+                data.push(PollColumn::new(slot))
+            }
+            continue;
+        }
+        // Parse the end time
+        if i == 2 {
+            // Skip "to" column
+            for (time, poll_column) in r?.iter().skip(1).zip(&mut data) {
+                poll_column.time.end = parse_time(time);
             }
             continue;
         }
@@ -46,3 +51,8 @@ pub fn read_data(file_name: &str) -> Result<PollData, Box<dyn Error>> {
 
     Ok(data)
 }
+
+/// Parse a time like "Mon, Jul 5, 2021 12:00 AM"
+fn parse_time(time: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(time, "%a, %b %e, %Y %l:%M %p").ok()
+}
@@ -0,0 +1,114 @@
+//! # Progress
+//! Reports on running searches, e.g. to show a progress display.
+//!
+//! The solvers report to `SchedulingOptions::observer` at most every `REPORT_INTERVAL`,
+//! whenever a branch of the exhaustive search is done, and once they finish.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often progress is reported at most, apart from finished branches
+pub const REPORT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether to report is checked whenever the number of evaluated schedules has none of these bits set
+const CHECK_MASK: u64 = 63;
+
+/// Receives progress reports while scheduling.
+///
+/// The exhaustive search reports from several threads, so observers must be `Send + Sync`.
+pub trait Observer: Debug + Send + Sync {
+    fn progress(&self, progress: &Progress);
+}
+
+/// The state of a running search
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// The number of branches of the exhaustive search that are done, one for each way to fill the first slot
+    pub branches_done: usize,
+    /// The total number of branches, or 0 for the other solvers
+    pub branches: usize,
+    /// The number of complete schedules evaluated so far
+    pub schedules: u64,
+    /// The cost of the best valid schedule found so far
+    pub best_cost: Option<f32>,
+    pub elapsed: Duration,
+    /// Whether the search is over
+    pub finished: bool,
+}
+
+/// Collects the progress of all threads of a search
+pub(crate) struct Tracker<'o> {
+    observer: Option<&'o dyn Observer>,
+    start: Instant,
+    branches: usize,
+    branches_done: AtomicUsize,
+    schedules: AtomicU64,
+    /// The bits of the best cost, whose order matches that of the costs as they are not negative
+    best_cost: AtomicU32,
+    last_report: Mutex<Instant>,
+}
+
+impl<'o> Tracker<'o> {
+    pub(crate) fn new(observer: Option<&'o dyn Observer>, branches: usize) -> Tracker<'o> {
+        let start = Instant::now();
+        Tracker {
+            observer,
+            start,
+            branches,
+            branches_done: AtomicUsize::new(0),
+            schedules: AtomicU64::new(0),
+            best_cost: AtomicU32::new(f32::INFINITY.to_bits()),
+            last_report: Mutex::new(start),
+        }
+    }
+
+    /// Count an evaluated schedule, with its cost if it is valid
+    pub(crate) fn evaluated(&self, cost: Option<f32>) {
+        if let Some(cost) = cost {
+            self.best_cost.fetch_min(cost.to_bits(), Ordering::Relaxed);
+        }
+        let schedules = self.schedules.fetch_add(1, Ordering::Relaxed) + 1;
+        if schedules & CHECK_MASK == 0 {
+            self.step();
+        }
+    }
+
+    /// Report if it is time to, e.g. during a long step of the search that evaluates no schedules
+    pub(crate) fn step(&self) {
+        if self.observer.is_none() {
+            return;
+        }
+        // Whichever thread gets here first reports, the others carry on
+        if let Ok(mut last_report) = self.last_report.try_lock() {
+            if last_report.elapsed() >= REPORT_INTERVAL {
+                *last_report = Instant::now();
+                self.report(false);
+            }
+        }
+    }
+
+    pub(crate) fn branch_done(&self) {
+        self.branches_done.fetch_add(1, Ordering::Relaxed);
+        self.report(false);
+    }
+
+    pub(crate) fn finish(&self) {
+        self.report(true);
+    }
+
+    fn report(&self, finished: bool) {
+        if let Some(observer) = self.observer {
+            let best_cost = f32::from_bits(self.best_cost.load(Ordering::Relaxed));
+            observer.progress(&Progress {
+                branches_done: self.branches_done.load(Ordering::Relaxed),
+                branches: self.branches,
+                schedules: self.schedules.load(Ordering::Relaxed),
+                best_cost: Some(best_cost).filter(|cost| cost.is_finite()),
+                elapsed: self.start.elapsed(),
+                finished,
+            });
+        }
+    }
+}
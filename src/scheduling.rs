@@ -2,49 +2,401 @@
 //!
 //! This is probably in need of being refactored into smaller modules.
 
+use crate::annealing;
+use crate::constraints::{Pairing, Pin, ShiftLimits};
+use crate::cost;
+use crate::cost::{CostTerm, TermCost};
 use crate::data::*;
-use scoped_threadpool::Pool;
-use std::collections::HashMap;
+use crate::ilp;
+use crate::progress::{Observer, Tracker};
+use chrono::NaiveDateTime;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::error::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Options for the scheduling algorithm
 #[derive(Debug)]
 pub struct SchedulingOptions {
     /// Whether to insert placeholders if a slot cannot be filled, or abort
     pub ignore_empty_slots: bool,
+    /// How many people are needed per slot, unless overridden by `PollColumn::headcount`
+    pub headcount: usize,
+    /// Per-person hard limits on the number of shifts
+    pub limits: HashMap<Name, ShiftLimits>,
+    /// Each person's workload in earlier polls, which the fairness term adds to their current workload
+    pub history: HashMap<Name, f32>,
+    /// The roles of each person, which `PollColumn::roles` may require
+    pub roles: HashMap<Name, Vec<Role>>,
+    /// People who must take particular slots
+    pub pins: Vec<Pin>,
+    /// People who should or must work together or apart
+    pub pairings: Vec<Pairing>,
+    /// The assignments of a previously published schedule, which are kept where possible
+    pub previous: Vec<Pin>,
+    /// The minimum number of slots between two shifts of the same person, e.g. 1 to avoid consecutive shifts
+    pub min_rest: usize,
+    /// How the distance between shifts is measured by the cost function
+    pub spacing: Spacing,
+    /// How each person's workload is measured, unless overridden by `PollColumn::weight`
+    pub load: Load,
+    /// Weights of the built-in terms of the cost function
+    pub weights: CostWeights,
+    /// The terms of the cost function, see `cost::builtin_terms`
+    pub cost_terms: Vec<Box<dyn CostTerm>>,
+    /// The algorithm used to find schedules
+    pub solver: Solver,
+    /// How long the search may take. The exhaustive and ILP solvers then return the best schedules
+    /// found so far, while the annealing solver always searches this long, see `annealing::DEFAULT_TIME_LIMIT`
+    pub time_limit: Option<Duration>,
+    /// Stops the search like `time_limit` once cancelled, e.g. from another thread
+    pub cancel: CancelToken,
+    /// Receives progress reports while scheduling
+    pub observer: Option<Box<dyn Observer>>,
+    /// Shuffle the order in which people are tried reproducibly, see `NameOrder`
+    pub seed: Option<u64>,
+    /// The number of threads the exhaustive search uses, by default one per physical core
+    pub threads: Option<usize>,
+    /// How many of the best schedules to return
+    pub top: usize,
+    /// How the alternatives to the best schedule must differ from it
+    pub diversity: Diversity,
 }
 
 impl Default for SchedulingOptions {
     fn default() -> SchedulingOptions {
         SchedulingOptions {
             ignore_empty_slots: false,
+            headcount: 1,
+            limits: HashMap::new(),
+            history: HashMap::new(),
+            roles: HashMap::new(),
+            pins: Vec::new(),
+            pairings: Vec::new(),
+            previous: Vec::new(),
+            min_rest: 0,
+            spacing: Spacing::Slots,
+            load: Load::Shifts,
+            weights: CostWeights::default(),
+            cost_terms: cost::builtin_terms(),
+            solver: Solver::Exhaustive,
+            time_limit: None,
+            cancel: CancelToken::default(),
+            observer: None,
+            seed: None,
+            threads: None,
+            top: 2,
+            diversity: Diversity::None,
         }
     }
 }
 
+impl SchedulingOptions {
+    /// Whether the search started at `start` has to stop, see `time_limit` and `cancel`
+    pub(crate) fn stopped(&self, start: Instant) -> bool {
+        self.cancel.is_cancelled()
+            || matches!(self.time_limit, Some(limit) if start.elapsed() >= limit)
+    }
+
+    /// The number of threads the exhaustive search uses
+    pub fn thread_count(&self) -> usize {
+        // We are CPU-bound, so don't attempt hyper-threading
+        self.threads.unwrap_or_else(num_cpus::get_physical)
+    }
+
+    /// The number of people needed for the given slot
+    pub fn headcount_of(&self, column: &PollColumn) -> usize {
+        column.headcount.unwrap_or(self.headcount)
+    }
+
+    /// How many placeholders the given slot may get: one for every place too few people are available for,
+    /// or one for a role nobody may be able to fill, and none without `ignore_empty_slots`
+    pub(crate) fn placeholders_allowed(&self, column: &PollColumn) -> usize {
+        if !self.ignore_empty_slots {
+            return 0;
+        }
+        let available = column
+            .responses
+            .values()
+            .filter(|response| !matches!(response, Response::No))
+            .count();
+        let headcount = self.headcount_of(column);
+        match headcount.saturating_sub(available) {
+            0 if headcount > 0 && !column.roles.is_empty() => 1,
+            missing => missing,
+        }
+    }
+
+    /// How much the given slot counts towards the workload of each person assigned to it.
+    ///
+    /// Measuring in hours falls back to 1 if the slot's end is unknown.
+    pub fn load_of(&self, column: &PollColumn) -> f32 {
+        match (column.weight, self.load) {
+            (Some(weight), _) => weight,
+            (None, Load::Hours) => column.time.hours().unwrap_or(1.0),
+            (None, Load::Shifts) => 1.0,
+        }
+    }
+
+    /// The person's workload in earlier polls
+    pub fn history_of(&self, name: &str) -> f32 {
+        *self.history.get(name).unwrap_or(&0.0)
+    }
+
+    /// Whether the person has the given role
+    pub fn has_role(&self, name: &str, role: &str) -> bool {
+        self.roles
+            .get(name)
+            .into_iter()
+            .flatten()
+            .any(|r| r == role)
+    }
+
+    /// Whether the people assigned to a slot cover all the roles it requires.
+    ///
+    /// A placeholder stands in for the missing qualified people.
+    pub fn roles_covered(&self, column: &PollColumn, names: &[&str]) -> bool {
+        names.contains(&"??")
+            || column
+                .roles
+                .iter()
+                .all(|role| names.iter().any(|name| self.has_role(name, role)))
+    }
+
+    /// Whether the people assigned to a slot respect all pairings without a cost
+    pub(crate) fn hard_pairings_respected(&self, names: &[&str]) -> bool {
+        self.pairings
+            .iter()
+            .filter(|pairing| pairing.cost.is_none())
+            .all(|pairing| !pairing.violated_by(names))
+    }
+
+    /// Whether all slots count the same towards the workload
+    pub(crate) fn uniform_load(&self, data: &[PollColumn]) -> bool {
+        data.windows(2)
+            .all(|pair| self.load_of(&pair[0]) == self.load_of(&pair[1]))
+    }
+
+    /// The position of each slot along which distances are measured, see `Spacing`.
+    ///
+    /// Measuring in days falls back to slots unless every slot has a start time.
+    pub fn slot_positions(&self, data: &[PollColumn]) -> Vec<f32> {
+        let starts = data
+            .iter()
+            .map(|day| day.time.start)
+            .collect::<Option<Vec<NaiveDateTime>>>();
+        match (self.spacing, starts) {
+            (Spacing::Days, Some(starts)) if !starts.is_empty() => starts
+                .iter()
+                .map(|start| (*start - starts[0]).num_seconds() as f32 / SECONDS_PER_DAY)
+                .collect(),
+            _ => (0..data.len()).map(|slot| slot as f32).collect(),
+        }
+    }
+
+    /// Whether two different slots are too close for one person to take both
+    pub fn too_close(&self, slot: usize, other: usize) -> bool {
+        slot != other && slot.max(other) - slot.min(other) <= self.min_rest
+    }
+
+    /// Whether the person must take the slot with the given index
+    pub fn is_pinned(&self, slot: usize, name: &str) -> bool {
+        self.pins
+            .iter()
+            .any(|pin| pin.slot == slot && pin.name == name)
+    }
+
+    /// The names of the people pinned to each slot, borrowed from the poll data.
+    ///
+    /// Pins to unknown slots or people are skipped, `check_constraints` reports them.
+    pub(crate) fn pinned_names<'data>(&self, data: &'data [PollColumn]) -> Vec<Vec<&'data str>> {
+        let mut pinned = vec![Vec::new(); data.len()];
+        for pin in &self.pins {
+            if let Some((name, _)) = data
+                .get(pin.slot)
+                .and_then(|day| day.responses.get_key_value(&pin.name))
+            {
+                if !pinned[pin.slot].contains(&name.as_str()) {
+                    pinned[pin.slot].push(name.as_str());
+                }
+            }
+        }
+        pinned
+    }
+}
+
+/// Stops a running search when cancelled.
+///
+/// Clones share the same state, so a clone can be kept to cancel the search from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The algorithm used to find schedules
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Solver {
+    /// Search all valid schedules, which finds the optimum but can take very long
+    Exhaustive,
+    /// Simulated annealing, which finds good schedules within a time limit
+    Annealing,
+    /// Integer programming, which finds the optimum for many mid-sized polls much faster
+    Ilp,
+}
+
+impl FromStr for Solver {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Solver, SimpleError> {
+        match s.to_lowercase().as_str() {
+            "exhaustive" => Ok(Solver::Exhaustive),
+            "annealing" => Ok(Solver::Annealing),
+            "ilp" => Ok(Solver::Ilp),
+            _ => Err(SimpleError::new(format!("Invalid solver: {}", s))),
+        }
+    }
+}
+
+/// How the distance between two shifts is measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Spacing {
+    /// The number of slots in between, regardless of when they take place
+    Slots,
+    /// The time between the starts of the slots, in days
+    Days,
+}
+
+impl FromStr for Spacing {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Spacing, SimpleError> {
+        match s.to_lowercase().as_str() {
+            "slots" => Ok(Spacing::Slots),
+            "days" => Ok(Spacing::Days),
+            _ => Err(SimpleError::new(format!("Invalid spacing: {}", s))),
+        }
+    }
+}
+
+const SECONDS_PER_DAY: f32 = 24.0 * 60.0 * 60.0;
+
+/// How each person's workload is measured for equal shift distribution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Load {
+    /// Every shift counts the same
+    Shifts,
+    /// Shifts count with their length in hours
+    Hours,
+}
+
+impl FromStr for Load {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Load, SimpleError> {
+        match s.to_lowercase().as_str() {
+            "shifts" => Ok(Load::Shifts),
+            "hours" => Ok(Load::Hours),
+            _ => Err(SimpleError::new(format!("Invalid load: {}", s))),
+        }
+    }
+}
+
+/// How alternative schedules must differ from the better ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Diversity {
+    /// Any schedules, which often differ by a single swap
+    None,
+    /// The cheapest schedules that differ from each better one picked in at least this many assignments
+    MinDifference(usize),
+    /// Among the schedules that cost at most this much more than the best one,
+    /// pick those that differ the most from each other
+    CostTolerance(f32),
+}
+
+/// Weights of the built-in terms of the cost function.
+///
+/// The defaults favour equal shift distribution over equal distances over avoiding IfNeedBe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostWeights {
+    /// Multiplied with the square of each person's number of shifts
+    pub occurrences: f32,
+    /// Multiplied with the inverse square of each person's average distance between shifts
+    pub distance: f32,
+    /// Cost of every IfNeedBe response that is used
+    pub ifneedbe: f32,
+    /// Cost of every `??` placeholder
+    pub placeholder: f32,
+    /// Cost of every assignment of `SchedulingOptions::previous` that is changed
+    pub change: f32,
+}
+
+impl Default for CostWeights {
+    fn default() -> CostWeights {
+        CostWeights {
+            occurrences: 1.0,
+            distance: 1.0,
+            ifneedbe: 0.25,
+            placeholder: 5.0,
+            change: 10.0,
+        }
+    }
+}
+
+/// One person assigned to a slot
 #[derive(Debug, Clone)]
-pub struct ScheduleEntry<'data, 'b> {
-    pub time: &'data Slot,
+pub struct Assignee<'b> {
     pub name: &'b str,
     pub ifneedbe: bool,
 }
 
+impl<'b> Assignee<'b> {
+    fn new(name: &'b str) -> Assignee<'b> {
+        Assignee {
+            name,
+            ifneedbe: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleEntry<'data, 'b> {
+    pub time: &'data Slot,
+    pub assignees: Vec<Assignee<'b>>,
+}
+
 impl<'data, 'b> ScheduleEntry<'data, 'b> {
-    fn new(time: &'data Slot, name: &'b str) -> ScheduleEntry<'data, 'b> {
+    pub(crate) fn new(time: &'data Slot, names: &[&'b str]) -> ScheduleEntry<'data, 'b> {
         ScheduleEntry {
             time,
-            name,
-            ifneedbe: false,
+            assignees: names.iter().map(|name| Assignee::new(name)).collect(),
         }
     }
+
+    /// Whether the given person is assigned to this slot
+    pub fn contains(&self, name: &str) -> bool {
+        self.assignees.iter().any(|a| a.name == name)
+    }
 }
 
 pub type Schedule<'data, 'b> = Vec<ScheduleEntry<'data, 'b>>;
 
 fn occur(s: &[ScheduleEntry], n: &str) -> usize {
-    s.iter()
-        .filter(|&ScheduleEntry { name, .. }| *name == n)
-        .count()
+    s.iter().filter(|entry| entry.contains(n)).count()
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +404,10 @@ pub struct EvaluatedSchedule<'data, 'b> {
     pub entries: Schedule<'data, 'b>,
     pub cost: f32,
     pub name_counts: Vec<(&'b str, usize)>,
+    /// Each person's workload, see `SchedulingOptions::load_of`
+    pub loads: Vec<(&'b str, f32)>,
+    /// What each cost term contributes, only filled in for the returned schedules
+    pub breakdown: Vec<TermCost>,
 }
 
 impl<'data, 'b> EvaluatedSchedule<'data, 'b> {
@@ -59,94 +415,610 @@ impl<'data, 'b> EvaluatedSchedule<'data, 'b> {
         entries: Schedule<'data, 'b>,
         cost: f32,
         name_counts: Vec<(&'b str, usize)>,
+        loads: Vec<(&'b str, f32)>,
     ) -> EvaluatedSchedule<'data, 'b> {
         EvaluatedSchedule {
             entries,
             cost,
             name_counts,
+            loads,
+            breakdown: Vec::new(),
         }
     }
 
+    /// Fill in `breakdown`
+    pub fn explain(&mut self, data: &[PollColumn], opts: &SchedulingOptions) {
+        self.breakdown = cost::breakdown(&self.entries, data, opts);
+    }
+
     // TODO impl Display instead
-    pub fn print(&self) {
+    pub fn print(&self, format: &SlotFormat) {
         let mut counts = self.name_counts.clone();
         counts.sort();
 
         for entry in &self.entries {
-            println!(
-                "{}:\t{}{}",
-                entry.time,
-                entry.name,
-                if entry.ifneedbe { "?" } else { "" }
-            );
+            let names = entry
+                .assignees
+                .iter()
+                .map(|a| format!("{}{}", a.name, if a.ifneedbe { "?" } else { "" }))
+                .collect::<Vec<String>>();
+            println!("{}:\t{}", format.format(entry.time), names.join(", "));
         }
         println!("\nCost: {}", self.cost);
+        for term in self.breakdown.iter().filter(|term| term.total != 0.0) {
+            println!("  {}: {}", term.term, term.total);
+        }
         println!("\nStats:");
-        for (name, count) in counts {
-            println!("{}: {}", name, count)
+        // Only weighted slots make the workload differ from the number of shifts
+        let weighted = counts
+            .iter()
+            .zip(&self.loads)
+            .any(|(&(_, count), &(_, load))| (load - count as f32).abs() > f32::EPSILON);
+        for ((name, count), (_, load)) in counts.into_iter().zip(&self.loads) {
+            if weighted {
+                println!("{}: {} (load: {})", name, count, load)
+            } else {
+                println!("{}: {}", name, count)
+            }
         }
         println!();
     }
 
-    pub fn write_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
+    /// Write one row per assignee, which `constraints::read_schedule` reads back with the same format
+    pub fn write_csv(&self, path: &str, format: &SlotFormat) -> Result<(), Box<dyn Error>> {
         let mut writer = csv::Writer::from_path(path)?;
         writer.write_record(&["slot", "name", "ifneedbe"])?;
 
+        // One row per assignee, so slots with several people appear several times
         for entry in &self.entries {
-            writer.write_record(&[&entry.time, entry.name, &entry.ifneedbe.to_string()])?;
+            let time = format.format(entry.time);
+            for assignee in &entry.assignees {
+                writer.write_record([&time, assignee.name, &assignee.ifneedbe.to_string()])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Print the cost each person causes, by term
+    pub fn print_explanation(&self) {
+        println!("Cost per person:");
+        let mut people = BTreeMap::new();
+        for term in &self.breakdown {
+            for (name, &cost) in term.per_person.iter().filter(|&(_, &cost)| cost != 0.0) {
+                people
+                    .entry(name.as_str())
+                    .or_insert_with(Vec::new)
+                    .push(format!("{} {}", term.term, cost));
+            }
+        }
+        for (name, costs) in people {
+            println!("{}: {}", name, costs.join(", "));
+        }
+        println!();
+    }
+
+    /// Write one `term,name,cost` row per person and term.
+    ///
+    /// Other terms get a single row without a name, unless they cost nothing.
+    pub fn write_breakdown_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["term", "name", "cost"])?;
+        for term in &self.breakdown {
+            if term.per_person.is_empty() && term.total != 0.0 {
+                writer.write_record([&term.term, "", &term.total.to_string()])?;
+            }
+            for (name, cost) in &term.per_person {
+                writer.write_record([&term.term, name, &cost.to_string()])?;
+            }
         }
         writer.flush()?;
         Ok(())
     }
+
+    /// The number of assignments in this schedule that are not in `other`
+    pub fn difference(&self, other: &EvaluatedSchedule) -> usize {
+        self.entries
+            .iter()
+            .zip(&other.entries)
+            .map(|(a, b)| {
+                a.assignees
+                    .iter()
+                    .filter(|assignee| !b.contains(assignee.name))
+                    .count()
+            })
+            .sum()
+    }
 }
 
+/// How many candidates are kept for `Diversity::MinDifference` and `Diversity::CostTolerance`, cheapest first
+const CANDIDATE_POOL: usize = 1000;
+
+/// The best schedules found, in order of increasing cost
 #[derive(Debug, Clone)]
-pub enum BestSchedules<'data, 'b> {
-    One(EvaluatedSchedule<'data, 'b>),
-    Two(EvaluatedSchedule<'data, 'b>, EvaluatedSchedule<'data, 'b>),
-    None,
+pub struct BestSchedules<'data, 'b> {
+    schedules: Vec<EvaluatedSchedule<'data, 'b>>,
+    capacity: usize,
+    diversity: Diversity,
+    optimal: bool,
 }
 
 impl<'data, 'b> BestSchedules<'data, 'b> {
-    // NOTE Maybe this could implement the trait for `+`?
-    fn add(
-        best: &BestSchedules<'data, 'b>,
-        new: EvaluatedSchedule<'data, 'b>,
-    ) -> BestSchedules<'data, 'b> {
-        match best {
-            BestSchedules::One(r1) => {
-                if r1.cost > new.cost {
-                    BestSchedules::Two(new, r1.clone())
-                } else {
-                    BestSchedules::Two(r1.clone(), new)
+    /// An empty collection that keeps at most `capacity` schedules
+    pub fn new(capacity: usize, diversity: Diversity) -> BestSchedules<'data, 'b> {
+        BestSchedules {
+            schedules: Vec::with_capacity(capacity + 1),
+            capacity,
+            diversity,
+            optimal: true,
+        }
+    }
+
+    /// The schedules, best first
+    pub fn schedules(&self) -> &[EvaluatedSchedule<'data, 'b>] {
+        &self.schedules
+    }
+
+    /// The best schedule, if any was found
+    pub fn best(&self) -> Option<&EvaluatedSchedule<'data, 'b>> {
+        self.schedules.first()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    /// Whether the search proved that no better schedules exist.
+    ///
+    /// This is false if the search was stopped early or the solver cannot prove optimality.
+    pub fn is_optimal(&self) -> bool {
+        self.optimal
+    }
+
+    /// Mark the schedules as not proven optimal
+    pub(crate) fn unproven(&mut self) {
+        self.optimal = false;
+    }
+
+    pub fn into_vec(self) -> Vec<EvaluatedSchedule<'data, 'b>> {
+        self.schedules
+    }
+
+    /// Insert a schedule at its place, dropping the worst one if there are too many
+    pub(crate) fn add(&mut self, new: EvaluatedSchedule<'data, 'b>, order: &NameOrder) {
+        let position = self
+            .schedules
+            .iter()
+            .position(|s| order.prefers(&new, s))
+            .unwrap_or(self.schedules.len());
+
+        if self.diversity != Diversity::None {
+            // Keep the cheapest candidates, `finish` picks among them
+            self.schedules.insert(position, new);
+            if let Diversity::CostTolerance(tolerance) = self.diversity {
+                let limit = self.schedules[0].cost + tolerance;
+                self.schedules.retain(|s| s.cost <= limit);
+            }
+            self.schedules.truncate(CANDIDATE_POOL);
+            return;
+        }
+
+        if position < self.capacity {
+            self.schedules.insert(position, new);
+            self.schedules.truncate(self.capacity);
+        }
+    }
+
+    /// Whether a schedule with the same assignments is already included
+    pub(crate) fn contains(&self, schedule: &EvaluatedSchedule) -> bool {
+        self.schedules.iter().any(|other| {
+            other.entries.iter().zip(&schedule.entries).all(|(a, b)| {
+                a.assignees.len() == b.assignees.len()
+                    && a.assignees.iter().all(|assignee| b.contains(assignee.name))
+            })
+        })
+    }
+
+    /// The cost a new schedule has to beat in order to be kept, if there is one
+    pub(crate) fn worst_cost(&self) -> Option<f32> {
+        // Candidates that cost more than those of a full pool are dropped
+        let pool_cost = self.schedules.get(CANDIDATE_POOL - 1).map(|s| s.cost);
+        match self.diversity {
+            Diversity::None if self.schedules.len() < self.capacity => None,
+            Diversity::None => self.schedules.last().map(|s| s.cost),
+            Diversity::MinDifference(_) => pool_cost,
+            Diversity::CostTolerance(tolerance) => {
+                let limit = self.schedules.first().map(|s| s.cost + tolerance);
+                match pool_cost {
+                    Some(pool_cost) => limit.map(|limit| limit.min(pool_cost)),
+                    None => limit,
                 }
             }
-            BestSchedules::Two(r1, r2) => {
-                if r1.cost > new.cost {
-                    BestSchedules::Two(new, r1.clone())
-                } else if r2.cost > new.cost {
-                    BestSchedules::Two(r1.clone(), new)
-                } else {
-                    BestSchedules::Two(r1.clone(), r2.clone())
+        }
+    }
+
+    /// The cost above which schedules that are added in order of increasing cost cannot change the result,
+    /// if there is one
+    pub(crate) fn final_cost(&self) -> Option<f32> {
+        if let Diversity::MinDifference(min) = self.diversity {
+            let picks = self.greedy_picks(min);
+            if picks.len() < self.capacity {
+                return None;
+            }
+            return picks.last().map(|&i| self.schedules[i].cost);
+        }
+        self.worst_cost()
+    }
+
+    /// The indices of the candidates that differ from every cheaper one picked before them in at least `min` assignments
+    fn greedy_picks(&self, min: usize) -> Vec<usize> {
+        let mut picks: Vec<usize> = Vec::new();
+        for (i, candidate) in self.schedules.iter().enumerate() {
+            if picks.len() == self.capacity {
+                break;
+            }
+            if picks
+                .iter()
+                .all(|&pick| self.schedules[pick].difference(candidate) >= min)
+            {
+                picks.push(i);
+            }
+        }
+        picks
+    }
+
+    /// Pick the final schedules once all have been added.
+    ///
+    /// For `Diversity::MinDifference`, these are the cheapest candidates that differ enough from the cheaper ones picked.
+    /// For `Diversity::CostTolerance`, this starts with the best schedule and repeatedly
+    /// adds the candidate whose smallest difference to those picked so far is largest.
+    pub(crate) fn finish(mut self) -> BestSchedules<'data, 'b> {
+        if let Diversity::MinDifference(min) = self.diversity {
+            let picks = self.greedy_picks(min);
+            let candidates = std::mem::take(&mut self.schedules);
+            self.schedules = candidates
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| picks.contains(i))
+                .map(|(_, schedule)| schedule)
+                .collect();
+        }
+        if let Diversity::CostTolerance(_) = self.diversity {
+            let candidates = std::mem::take(&mut self.schedules);
+            // For each candidate, the smallest difference to the picked schedules, or `None` once picked
+            let mut differences = vec![Some(usize::MAX); candidates.len()];
+            for _ in 0..self.capacity.min(candidates.len()) {
+                // `max_by_key` returns the last maximum, so iterate in reverse to prefer cheaper candidates
+                let next = (0..candidates.len())
+                    .rev()
+                    .filter(|&i| differences[i].is_some())
+                    .max_by_key(|&i| differences[i])
+                    .unwrap();
+                differences[next] = None;
+                for (candidate, difference) in candidates.iter().zip(&mut differences) {
+                    if let Some(difference) = difference {
+                        *difference = (*difference).min(candidate.difference(&candidates[next]));
+                    }
                 }
             }
-            BestSchedules::None => BestSchedules::One(new),
+            self.schedules = candidates
+                .into_iter()
+                .zip(differences)
+                .filter(|(_, difference)| difference.is_none())
+                .map(|(schedule, _)| schedule)
+                .collect();
+        }
+        self
+    }
+
+    /// Fill in the cost breakdown of every schedule
+    pub(crate) fn explain(mut self, data: &[PollColumn], opts: &SchedulingOptions) -> Self {
+        for schedule in &mut self.schedules {
+            schedule.explain(data, opts);
+        }
+        self
+    }
+
+    fn merge(&mut self, other: BestSchedules<'data, 'b>, order: &NameOrder) {
+        self.optimal &= other.optimal;
+        for schedule in other.schedules {
+            self.add(schedule, order);
+        }
+    }
+}
+
+/// Check that the constraints in `opts` can be met at all.
+///
+/// This only catches obvious conflicts, so the search may still find no valid schedule.
+pub fn check_constraints(data: &[PollColumn], opts: &SchedulingOptions) -> Result<(), SimpleError> {
+    let availability = |person: &str| {
+        data.iter()
+            .filter(|day| {
+                matches!(
+                    day.responses.get(person),
+                    Some(Response::Yes) | Some(Response::IfNeedBe)
+                )
+            })
+            .count()
+    };
+    let demand: usize = data.iter().map(|day| opts.headcount_of(day)).sum();
+
+    if opts.top == 0 {
+        return Err(SimpleError::new("At least one schedule must be requested"));
+    }
+    if opts.threads == Some(0) {
+        return Err(SimpleError::new("At least one thread is needed"));
+    }
+
+    // Costs must never be negative, see `CostTerm`
+    let weights = &opts.weights;
+    let named_weights = [
+        ("occurrences", weights.occurrences),
+        ("distance", weights.distance),
+        ("ifneedbe", weights.ifneedbe),
+        ("placeholder", weights.placeholder),
+        ("change", weights.change),
+    ];
+    if let Some((name, _)) = named_weights
+        .iter()
+        .find(|(_, weight)| !weight.is_finite() || *weight < 0.0)
+    {
+        return Err(SimpleError::new(format!(
+            "The {} weight must be a number that is not negative",
+            name
+        )));
+    }
+
+    if opts.solver == Solver::Ilp {
+        // Picking among the candidates would mean enumerating every schedule within the tolerance
+        if let Diversity::CostTolerance(_) = opts.diversity {
+            return Err(SimpleError::new(
+                "The ilp solver does not support a cost tolerance, try --min-difference",
+            ));
+        }
+        let missing = cost::builtin_terms()
+            .into_iter()
+            .find(|term| !opts.cost_terms.iter().any(|t| t.name() == term.name()));
+        if let Some(term) = missing {
+            return Err(SimpleError::new(format!(
+                "The ilp solver models the built-in cost terms, but {} is missing",
+                term.name()
+            )));
+        }
+    }
+
+    let mut limited_names = opts.limits.keys().collect::<Vec<&Name>>();
+    limited_names.sort();
+    for name in limited_names {
+        let limits = &opts.limits[name];
+        if !data.iter().any(|day| day.responses.contains_key(name)) {
+            return Err(SimpleError::new(format!(
+                "Shift limits given for unknown person {}",
+                name
+            )));
+        }
+        if let Some(max) = limits.max {
+            if max < limits.min {
+                return Err(SimpleError::new(format!(
+                    "The minimum of {} shifts for {} exceeds the maximum of {}",
+                    limits.min, name, max
+                )));
+            }
+        }
+        let available = availability(name);
+        if available < limits.min {
+            return Err(SimpleError::new(format!(
+                "{} needs at least {} shifts, but is only available for {} slots",
+                name, limits.min, available
+            )));
+        }
+    }
+
+    if let Some(day) = data
+        .iter()
+        .find(|day| matches!(day.weight, Some(weight) if !weight.is_finite() || weight < 0.0))
+    {
+        return Err(SimpleError::new(format!(
+            "The weight of {} must not be negative",
+            day.time
+        )));
+    }
+    if opts.load == Load::Hours {
+        let unknown = |day: &&PollColumn| day.weight.is_none() && day.time.hours().is_none();
+        if let Some(day) = data.iter().find(unknown) {
+            return Err(SimpleError::new(format!(
+                "Measuring the workload in hours needs the end of every slot, but {} has none",
+                day.time
+            )));
+        }
+    }
+
+    if !opts.ignore_empty_slots {
+        for day in data {
+            let available = |role: &&Role| {
+                day.responses.iter().any(|(name, response)| {
+                    !matches!(response, Response::No) && opts.has_role(name, role)
+                })
+            };
+            if let Some(role) = day.roles.iter().find(|role| !available(role)) {
+                return Err(SimpleError::new(format!(
+                    "{} needs a {}, but nobody with that role is available",
+                    day.time, role
+                )));
+            }
+        }
+    }
+
+    let mut histories = opts.history.iter().collect::<Vec<(&Name, &f32)>>();
+    histories.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    if let Some((name, _)) = histories
+        .iter()
+        .find(|(_, &total)| total < 0.0 || total.is_nan())
+    {
+        return Err(SimpleError::new(format!(
+            "The history of {} must not be negative",
+            name
+        )));
+    }
+
+    for pairing in &opts.pairings {
+        if pairing.first == pairing.second {
+            return Err(SimpleError::new(format!(
+                "{} cannot be paired with themselves",
+                pairing.first
+            )));
+        }
+        if matches!(pairing.cost, Some(cost) if !cost.is_finite() || cost < 0.0) {
+            return Err(SimpleError::new(format!(
+                "The cost of pairing {} and {} must be a number that is not negative",
+                pairing.first, pairing.second
+            )));
+        }
+        let responded = |name: &Name| data.iter().any(|day| day.responses.contains_key(name));
+        if let Some(name) = [&pairing.first, &pairing.second]
+            .iter()
+            .find(|name| !responded(name))
+        {
+            return Err(SimpleError::new(format!(
+                "{} is paired with someone, but did not respond to the poll",
+                name
+            )));
+        }
+    }
+
+    if opts.spacing == Spacing::Days {
+        if let Some(day) = data.iter().find(|day| day.time.start.is_none()) {
+            return Err(SimpleError::new(format!(
+                "Measuring distances in days needs start times, but {} has none",
+                day.time
+            )));
+        }
+    }
+
+    if let Some(pin) = opts.previous.iter().find(|pin| pin.slot >= data.len()) {
+        return Err(SimpleError::new(format!(
+            "{} was previously assigned to slot {}, but there are only {} slots",
+            pin.name,
+            pin.slot + 1,
+            data.len()
+        )));
+    }
+
+    let mut pins_per_slot = vec![0; data.len()];
+    let mut pins_per_person = HashMap::new();
+    for pin in &opts.pins {
+        let day = data.get(pin.slot).ok_or_else(|| {
+            SimpleError::new(format!(
+                "{} is pinned to slot {}, but there are only {} slots",
+                pin.name,
+                pin.slot + 1,
+                data.len()
+            ))
+        })?;
+        match day.responses.get(&pin.name) {
+            Some(Response::Yes) | Some(Response::IfNeedBe) => (),
+            Some(Response::No) => {
+                return Err(SimpleError::new(format!(
+                    "{} is pinned to {}, but responded No",
+                    pin.name, day.time
+                )))
+            }
+            None => {
+                return Err(SimpleError::new(format!(
+                    "{} is pinned to {}, but did not respond to the poll",
+                    pin.name, day.time
+                )))
+            }
+        }
+        pins_per_slot[pin.slot] += 1;
+        *pins_per_person.entry(pin.name.as_str()).or_insert(0) += 1;
+    }
+    for (day, &pins) in data.iter().zip(&pins_per_slot) {
+        if pins > opts.headcount_of(day) {
+            return Err(SimpleError::new(format!(
+                "{} people are pinned to {}, but only {} are needed",
+                pins,
+                day.time,
+                opts.headcount_of(day)
+            )));
         }
     }
+    let mut pinned_people = pins_per_person.into_iter().collect::<Vec<(&str, usize)>>();
+    pinned_people.sort_unstable();
+    for (name, pins) in pinned_people {
+        if let Some(ShiftLimits { max: Some(max), .. }) = opts.limits.get(name) {
+            if pins > *max {
+                return Err(SimpleError::new(format!(
+                    "{} is pinned to {} slots, but may take at most {} shifts",
+                    name, pins, max
+                )));
+            }
+        }
+    }
+    for (i, pin) in opts.pins.iter().enumerate() {
+        if let Some(other) = opts.pins[i + 1..]
+            .iter()
+            .find(|other| other.name == pin.name && opts.too_close(pin.slot, other.slot))
+        {
+            return Err(SimpleError::new(format!(
+                "{} is pinned to slots {} and {}, which are closer than the minimum rest",
+                pin.name,
+                pin.slot + 1,
+                other.slot + 1
+            )));
+        }
+    }
+
+    let min_sum: usize = opts.limits.values().map(|limits| limits.min).sum();
+    if min_sum > demand {
+        return Err(SimpleError::new(format!(
+            "The minimum limits add up to {} shifts, but only {} are available",
+            min_sum, demand
+        )));
+    }
 
-    fn merge(
-        r1: BestSchedules<'data, 'b>,
-        r2: BestSchedules<'data, 'b>,
-    ) -> BestSchedules<'data, 'b> {
-        match r1 {
-            BestSchedules::None => r2,
-            BestSchedules::One(b1) => BestSchedules::add(&r2, b1),
-            BestSchedules::Two(b1, b2) => BestSchedules::add(&BestSchedules::add(&r2, b1), b2),
+    if !opts.ignore_empty_slots {
+        let mut people = data
+            .iter()
+            .flat_map(|day| day.responses.keys())
+            .collect::<Vec<&Name>>();
+        people.sort();
+        people.dedup();
+
+        let mut capped = Vec::new();
+        let mut capacity = 0;
+        let mut unlimited_capacity = 0;
+        for person in people {
+            let available = availability(person);
+            unlimited_capacity += available;
+            match opts.limits.get(person) {
+                Some(ShiftLimits { max: Some(max), .. }) if *max < available => {
+                    capped.push(person.as_str());
+                    capacity += max;
+                }
+                _ => capacity += available,
+            }
+        }
+        if capacity < demand && unlimited_capacity >= demand {
+            return Err(SimpleError::new(format!(
+                "The maximum limits of {} only allow {} of the {} required shifts to be assigned",
+                capped.join(", "),
+                capacity,
+                demand
+            )));
         }
     }
+
+    Ok(())
 }
 
 /// Find the global cost minimum of all valid schedules
+///
+/// The cost of a schedule is the sum of `opts.cost_terms`.
+/// If `opts.solver` is not exhaustive, the result is the best that could be found instead.
+/// When the search is stopped by `opts.time_limit` or `opts.cancel`, it returns the best schedules
+/// found so far, see `BestSchedules::is_optimal`.
+/// The ilp solver does not support `Diversity::CostTolerance`, so the exhaustive search is used instead.
 pub fn compute_all_schedules<'data, 'b>(
     data: &'data [PollColumn],
     opts: &SchedulingOptions,
@@ -154,44 +1026,62 @@ pub fn compute_all_schedules<'data, 'b>(
 where
     'data: 'b,
 {
-    let first_day = &data[0];
-    // We are CPU-bound, so don't attempt hyper-threading
-    let mut pool = Pool::new(num_cpus::get_physical() as u32);
-
-    // Parallelize over the first day: one thread for the best solution starting with each name
-    let mut results = vec![BestSchedules::None; first_day.responses.len()];
-
-    // Using a scoped threadpool allows passing in non-static references.
-    // I need that because I know that the lifetime of the thread closures will not exceed that of this function.
-    pool.scoped(|scoped| {
-        // zip with results to avoid mutably indexing, which would confuse the borrow checker:
-        // It *looks* like multiple things depend on the vector, but they never actually collide
-        // (each thread gets it's own element)
-        for ((person, response), result) in first_day.responses.iter().zip(results.iter_mut()) {
-            match response {
-                Response::No if opts.ignore_empty_slots => {
-                    let starting_sched = vec![ScheduleEntry::new(&first_day.time, "??")];
-                    scoped.execute(move || {
-                        compute_all_schedules_(data, opts, starting_sched, result)
-                    });
-                }
-                Response::No => (),
-                _ => {
-                    let starting_sched = vec![ScheduleEntry::new(&first_day.time, &person)];
-                    scoped.execute(move || {
-                        compute_all_schedules_(data, opts, starting_sched, result)
-                    });
-                }
-            }
-        }
+    if opts.top == 0 {
+        return BestSchedules::new(0, opts.diversity);
+    }
+    let tolerance = matches!(opts.diversity, Diversity::CostTolerance(_));
+    let best = match opts.solver {
+        Solver::Annealing => annealing::compute_schedules(data, opts),
+        Solver::Ilp if !tolerance => ilp::compute_schedules(data, opts),
+        Solver::Ilp | Solver::Exhaustive => compute_exhaustive(data, opts),
+    };
+    best.finish().explain(data, opts)
+}
+
+/// Branch and bound over all schedules, in parallel
+fn compute_exhaustive<'data, 'b>(
+    data: &'data [PollColumn],
+    opts: &SchedulingOptions,
+) -> BestSchedules<'data, 'b>
+where
+    'data: 'b,
+{
+    let ctx = SearchContext::new(data, opts);
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(opts.thread_count())
+        .build()
+        .expect("Failed to start the threads");
+
+    // The branches starting with each way to fill the first slot are reported as progress,
+    // and the ones below them are split further, see `PARALLEL_DEPTH`
+    let first_day_options = ctx.slot_options(&[]);
+    let tracker = Tracker::new(opts.observer.as_deref(), first_day_options.len());
+    let results = pool.install(|| {
+        first_day_options
+            .into_par_iter()
+            .map(|entry| {
+                let mut result = BestSchedules::new(opts.top, opts.diversity);
+                compute_all_schedules_(&ctx, &tracker, vec![entry], &mut result);
+                tracker.branch_done();
+                result
+            })
+            .collect::<Vec<BestSchedules>>()
     });
 
     // Combine partial solutions
-    results.into_iter().fold(BestSchedules::None, |best, next| {
-        BestSchedules::merge(best, next)
-    })
+    let mut best = BestSchedules::new(opts.top, opts.diversity);
+    for result in results {
+        best.merge(result, &ctx.order);
+    }
+    tracker.finish();
+    best
 }
 
+/// Partial schedules shorter than this are extended in parallel, so that idle threads can steal their branches.
+///
+/// Deeper down, the overhead of splitting outweighs the gains.
+const PARALLEL_DEPTH: usize = 4;
+
 // Alternative implementation:
 // currently requires library features - wait for stabilization of `yield`
 //
@@ -205,104 +1095,421 @@ where
 //
 
 fn compute_all_schedules_<'data, 'b>(
-    data: &'data [PollColumn],
-    opts: &SchedulingOptions,
+    ctx: &SearchContext<'data, '_>,
+    tracker: &Tracker,
     cur_sched: Schedule<'data, 'b>,
     results: &mut BestSchedules<'data, 'b>,
 ) where
     'data: 'b,
 {
-    // Allow early cutoff: don't assign people much more than necessary and calculate cost, but drop immediately
-    let max_occur = data.len() / data[0].responses.len() + 1;
-
-    if cur_sched.len() == data.len() {
-        *results = BestSchedules::add(results, evaluate(cur_sched, data))
+    if !ctx.minimums_reachable(&cur_sched) {
+        return;
+    }
+    if ctx.opts.stopped(ctx.start) {
+        results.unproven();
+        return;
+    }
+    if cur_sched.len() == ctx.data.len() {
+        let evaluated = evaluate(cur_sched, ctx.data, ctx.opts);
+        tracker.evaluated(Some(evaluated.cost));
+        ctx.add_candidate_cost(evaluated.cost);
+        results.add(evaluated, &ctx.order);
+        if let Some(worst_cost) = results.worst_cost() {
+            ctx.tighten_cutoff(worst_cost);
+        }
     } else {
-        let day = &data[cur_sched.len()];
-        // NOTE since the hash is not deterministic, this implicitly shuffles the names
-        let mut valid_response_found = false;
-        for (person, response) in &day.responses {
-            // TODO this branch might have to be skipped if opts.ignore_empty_slots is set
-            if occur(&cur_sched, person) == max_occur {
-                continue;
-            }
-            match response {
-                Response::No => (),
-                _ => {
-                    valid_response_found = true;
+        // Branch and bound: skip partial schedules that cannot beat the ones we already have,
+        // including those found by other threads
+        let cutoff = match results.worst_cost() {
+            Some(worst_cost) => worst_cost.min(ctx.cutoff()),
+            None => ctx.cutoff(),
+        };
+        if cutoff.is_finite() && ctx.lower_bound(&cur_sched) > cutoff + BOUND_TOLERANCE {
+            return;
+        }
+        let options = ctx.slot_options(&cur_sched);
+        if cur_sched.len() < PARALLEL_DEPTH {
+            let branches = options
+                .into_par_iter()
+                .map(|entry| {
+                    let mut branch = BestSchedules::new(ctx.opts.top, ctx.opts.diversity);
                     let mut new_sched = cur_sched.clone();
-                    new_sched.push(ScheduleEntry::new(&day.time, &person));
-                    compute_all_schedules_(data, opts, new_sched, results);
+                    new_sched.push(entry);
+                    compute_all_schedules_(ctx, tracker, new_sched, &mut branch);
+                    branch
+                })
+                .collect::<Vec<BestSchedules>>();
+            for branch in branches {
+                results.merge(branch, &ctx.order);
+            }
+        } else {
+            for entry in options {
+                let mut new_sched = cur_sched.clone();
+                new_sched.push(entry);
+                compute_all_schedules_(ctx, tracker, new_sched, results);
+            }
+        }
+    }
+}
+
+/// Slack for rounding errors when comparing lower bounds and costs
+const BOUND_TOLERANCE: f32 = 1e-4;
+
+/// State shared by all branches of the search
+pub(crate) struct SearchContext<'data, 'o> {
+    data: &'data [PollColumn],
+    opts: &'o SchedulingOptions,
+    /// Allow early cutoff: don't assign people much more than necessary and calculate cost, but drop immediately
+    max_occur: usize,
+    /// For each slot, the number of slots from there on each person is available for
+    availability: Vec<HashMap<&'data str, usize>>,
+    /// The people pinned to each slot
+    pinned: Vec<Vec<&'data str>>,
+    /// For each slot, the number of slots from there on each person is pinned to
+    remaining_pins: Vec<HashMap<&'data str, usize>>,
+    order: NameOrder<'data>,
+    /// When the search started, see `SchedulingOptions::stopped`
+    start: Instant,
+    /// The bits of the lowest `BestSchedules::worst_cost` of all threads, see `cutoff`
+    cutoff: AtomicU32,
+    /// The bits of the cheapest costs found by all threads, when candidates are pooled, see `add_candidate_cost`
+    candidate_costs: Mutex<BinaryHeap<u32>>,
+}
+
+impl<'data, 'o> SearchContext<'data, 'o> {
+    pub(crate) fn new(
+        data: &'data [PollColumn],
+        opts: &'o SchedulingOptions,
+    ) -> SearchContext<'data, 'o> {
+        let assignments: usize = data.iter().map(|day| opts.headcount_of(day)).sum();
+        let people = data[0].responses.len();
+        let max_occur = assignments / people + 1;
+
+        // People with a lower maximum leave more shifts for everyone else
+        let lower_maxima = opts
+            .limits
+            .values()
+            .filter_map(|limits| limits.max)
+            .filter(|&max| max < max_occur)
+            .collect::<Vec<usize>>();
+        let max_occur = if lower_maxima.len() < people {
+            assignments.saturating_sub(lower_maxima.iter().sum()) / (people - lower_maxima.len())
+                + 1
+        } else {
+            max_occur
+        };
+        // With weighted slots, several light shifts may balance a heavy one
+        let max_occur = if opts.uniform_load(data) {
+            max_occur
+        } else {
+            let total_load: f32 = data
+                .iter()
+                .map(|day| opts.load_of(day) * opts.headcount_of(day) as f32)
+                .sum();
+            let (lightest, heaviest) = data
+                .iter()
+                .map(|day| opts.load_of(day))
+                .fold((f32::INFINITY, 0.0f32), |(lightest, heaviest), load| {
+                    (lightest.min(load), heaviest.max(load))
+                });
+            let max_load = total_load / people as f32 + heaviest;
+            if lightest > 0.0 {
+                ((max_load / lightest).ceil() as usize).max(max_occur)
+            } else {
+                data.len().max(max_occur)
+            }
+        };
+        // People with a shorter history may need to catch up
+        let max_occur = if opts.history.is_empty() {
+            max_occur
+        } else {
+            let histories = data[0]
+                .responses
+                .keys()
+                .map(|person| opts.history_of(person))
+                .collect::<Vec<f32>>();
+            let spread = histories.iter().fold(0.0f32, |max, &h| max.max(h))
+                - histories.iter().fold(f32::INFINITY, |min, &h| min.min(h));
+            let lightest = data
+                .iter()
+                .map(|day| opts.load_of(day))
+                .filter(|&load| load > 0.0)
+                .fold(f32::INFINITY, f32::min);
+            max_occur + (spread / lightest).ceil() as usize
+        };
+
+        let mut availability = vec![HashMap::new(); data.len() + 1];
+        for (i, day) in data.iter().enumerate().rev() {
+            let mut available = availability[i + 1].clone();
+            for (person, response) in &day.responses {
+                if !matches!(response, Response::No) {
+                    *available.entry(person.as_str()).or_insert(0) += 1;
                 }
             }
+            availability[i] = available;
+        }
+
+        let pinned = opts.pinned_names(data);
+        let mut remaining_pins = vec![HashMap::new(); data.len() + 1];
+        for i in (0..data.len()).rev() {
+            let mut remaining = remaining_pins[i + 1].clone();
+            for &person in &pinned[i] {
+                *remaining.entry(person).or_insert(0) += 1;
+            }
+            remaining_pins[i] = remaining;
         }
-        if !valid_response_found && opts.ignore_empty_slots {
-            let mut new_sched = cur_sched.clone();
-            new_sched.push(ScheduleEntry::new(&day.time, "??"));
-            compute_all_schedules_(data, opts, new_sched, results);
+
+        SearchContext {
+            data,
+            opts,
+            max_occur,
+            availability,
+            pinned,
+            remaining_pins,
+            order: NameOrder::new(data, opts),
+            start: Instant::now(),
+            cutoff: AtomicU32::new(f32::INFINITY.to_bits()),
+            candidate_costs: Mutex::new(BinaryHeap::new()),
         }
     }
-}
 
-fn calc_avg_distance_components(s: &[ScheduleEntry]) -> f32 {
-    let mut last_seen = HashMap::new();
-    let mut dsts = HashMap::new();
+    /// The number of shifts a person may be assigned at most, unless their limits or pins need more
+    pub(crate) fn max_occur(&self) -> usize {
+        self.max_occur
+    }
+
+    /// The cost that new schedules have to beat in every thread, or infinity if there is none yet.
+    ///
+    /// Schedules that are worse than those one thread keeps cannot be among the best overall.
+    fn cutoff(&self) -> f32 {
+        f32::from_bits(self.cutoff.load(Ordering::Relaxed))
+    }
+
+    fn tighten_cutoff(&self, cost: f32) {
+        // Costs are not negative, so their bits are ordered like them
+        self.cutoff.fetch_min(cost.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Record the cost of a complete schedule when candidates are pooled.
+    ///
+    /// The branches of one thread are often too small to fill a pool on their own,
+    /// but schedules that are worse than `CANDIDATE_POOL` others from any thread are dropped anyway.
+    fn add_candidate_cost(&self, cost: f32) {
+        if self.opts.diversity == Diversity::None {
+            return;
+        }
+        let mut costs = self.candidate_costs.lock().unwrap();
+        if costs.len() == CANDIDATE_POOL {
+            if cost.to_bits() >= *costs.peek().unwrap() {
+                return;
+            }
+            costs.pop();
+        }
+        costs.push(cost.to_bits());
+        if costs.len() == CANDIDATE_POOL {
+            self.tighten_cutoff(f32::from_bits(*costs.peek().unwrap()));
+        }
+    }
 
-    for (i, person) in s.iter().map(|e| &e.name).enumerate() {
-        let last_seen_i = last_seen.entry(person).or_insert(i);
-        let dsts = dsts.entry(person).or_insert_with(Vec::new);
-        let dst = (i - *last_seen_i) as f32;
-        if dst > 0.0 {
-            dsts.push(dst);
+    /// The maximum number of shifts a person may be assigned
+    pub(crate) fn cap(&self, person: &str) -> usize {
+        let pins = *self.remaining_pins[0].get(person).unwrap_or(&0);
+        match self.opts.limits.get(person) {
+            Some(limits) => limits
+                .max
+                .unwrap_or(usize::MAX)
+                .min(self.max_occur.max(limits.min).max(pins)),
+            None => self.max_occur.max(pins),
         }
-        last_seen.insert(person, i);
     }
 
-    let mut result = 0.0;
-    for dsts in dsts.values() {
-        let dst_sum: f32 = dsts.iter().sum();
-        let avg_dst = dst_sum / dsts.len() as f32;
-        if avg_dst > 0.0 {
-            result += 1.0 / (avg_dst * avg_dst);
+    /// A lower bound on the cost of every complete schedule starting with `partial`
+    fn lower_bound(&self, partial: &[ScheduleEntry]) -> f32 {
+        self.opts
+            .cost_terms
+            .iter()
+            .map(|term| term.lower_bound(partial, self.data, self.opts))
+            .sum()
+    }
+
+    /// Whether everyone can still get their minimum number of shifts
+    pub(crate) fn minimums_reachable(&self, cur_sched: &[ScheduleEntry]) -> bool {
+        let remaining = &self.availability[cur_sched.len()];
+        self.opts.limits.iter().all(|(person, limits)| {
+            limits.min == 0
+                || occur(cur_sched, person) + remaining.get(person.as_str()).unwrap_or(&0)
+                    >= limits.min
+        })
+    }
+
+    /// Whether the person took a shift too recently to take the slot following `cur_sched`,
+    /// or is pinned to one of the next slots
+    fn resting(&self, cur_sched: &[ScheduleEntry], person: &str) -> bool {
+        let slot = cur_sched.len();
+        let recent = slot.saturating_sub(self.opts.min_rest);
+        let upcoming = (slot + self.opts.min_rest + 1).min(self.data.len());
+        cur_sched[recent..]
+            .iter()
+            .any(|entry| entry.contains(person))
+            || self.pinned[slot + 1..upcoming]
+                .iter()
+                .any(|names| names.contains(&person))
+    }
+
+    /// All valid ways of filling the slot following `cur_sched`.
+    ///
+    /// If not enough people are available and `opts.ignore_empty_slots` is set,
+    /// the remaining places are filled with `??` placeholders.
+    pub(crate) fn slot_options<'b>(
+        &self,
+        cur_sched: &[ScheduleEntry<'data, 'b>],
+    ) -> Vec<ScheduleEntry<'data, 'b>>
+    where
+        'data: 'b,
+    {
+        let slot = cur_sched.len();
+        let day = &self.data[slot];
+        // Pinned people are always assigned, the others fill the remaining places
+        let pinned = &self.pinned[slot];
+        let headcount = self.opts.headcount_of(day).saturating_sub(pinned.len());
+
+        // Leave room for everyone's pins in the later slots
+        let remaining_pins = &self.remaining_pins[slot];
+        let mut candidates = day
+            .responses
+            .iter()
+            .filter(|(_, response)| !matches!(response, Response::No))
+            .map(|(person, _)| person.as_str())
+            .filter(|person| !pinned.contains(person))
+            .filter(|person| !self.resting(cur_sched, person))
+            .filter(|person| {
+                occur(cur_sched, person) + remaining_pins.get(person).unwrap_or(&0)
+                    < self.cap(person)
+            })
+            .collect::<Vec<&str>>();
+        self.order.sort(&mut candidates);
+
+        if candidates.len() < headcount && !self.opts.ignore_empty_slots {
+            return Vec::new();
+        }
+        let missing = headcount.saturating_sub(candidates.len());
+        // A placeholder may also take the place of a qualified person nobody can stand in for
+        let mut sizes = vec![headcount - missing];
+        if self.opts.ignore_empty_slots && missing == 0 && headcount > 0 && !day.roles.is_empty() {
+            sizes.push(headcount - 1);
+        }
+
+        sizes
+            .into_iter()
+            .flat_map(|size| combinations(&candidates, size))
+            .map(|names| {
+                let placeholders = headcount - names.len();
+                let mut names = pinned.iter().copied().chain(names).collect::<Vec<&str>>();
+                names.extend(std::iter::repeat_n("??", placeholders));
+                names
+            })
+            .filter(|names| self.opts.roles_covered(day, names))
+            .filter(|names| self.opts.hard_pairings_respected(names))
+            .map(|names| ScheduleEntry::new(&day.time, &names))
+            .collect()
+    }
+}
+
+/// All subsets of size `k` of the given items, preserving their order
+fn combinations<T: Copy>(items: &[T], k: usize) -> Vec<Vec<T>> {
+    if k == 0 {
+        return vec![Vec::new()];
+    }
+    let mut result = Vec::new();
+    for (i, &first) in items.iter().enumerate() {
+        for mut rest in combinations(&items[i + 1..], k - 1) {
+            rest.insert(0, first);
+            result.push(rest);
         }
     }
     result
 }
 
-fn calc_ifneedbe_components(s: &mut Schedule, data: &[PollColumn]) -> f32 {
-    let mut result = 0.0;
+/// Mark which assignments rely on an IfNeedBe response
+fn mark_ifneedbe(s: &mut Schedule, data: &[PollColumn]) {
     for (i, entry) in s.iter_mut().enumerate() {
-        if let Some(Response::IfNeedBe) = data[i].responses.get(entry.name) {
-            result += 0.25;
-            entry.ifneedbe = true;
-        }
-        // Penalize using placeholders
-        if entry.name == "??" {
-            result += 5.0;
+        for assignee in &mut entry.assignees {
+            if let Some(Response::IfNeedBe) = data[i].responses.get(assignee.name) {
+                assignee.ifneedbe = true;
+            }
         }
     }
-    result
 }
 
-fn evaluate<'data, 'b>(
+pub(crate) fn evaluate<'data, 'b>(
     mut s: Schedule<'data, 'b>,
     data: &[PollColumn],
+    opts: &SchedulingOptions,
 ) -> EvaluatedSchedule<'data, 'b> {
-    let mut cost = 0.0;
-    let mut person_occurrences = HashMap::new();
+    mark_ifneedbe(&mut s, data);
+    let cost = opts
+        .cost_terms
+        .iter()
+        .map(|term| term.cost(&s, data, opts))
+        .sum();
+    let occ_stats = cost::occurrences(&s).into_iter().collect();
+    let loads = cost::loads(&s, data, opts).into_iter().collect();
+
+    EvaluatedSchedule::new(s, cost, occ_stats, loads)
+}
+
+/// The order in which people are tried, which also breaks ties between schedules of equal cost.
+///
+/// Names are sorted alphabetically, or shuffled reproducibly if `SchedulingOptions::seed` is set.
+/// Placeholders come last.
+pub(crate) struct NameOrder<'data> {
+    ranks: HashMap<&'data str, usize>,
+}
 
-    for person in s.iter().map(|e| &e.name) {
-        let occ = person_occurrences.entry(person).or_insert(0);
-        *occ += 1;
+impl<'data> NameOrder<'data> {
+    pub(crate) fn new(data: &'data [PollColumn], opts: &SchedulingOptions) -> NameOrder<'data> {
+        let mut names = data
+            .iter()
+            .flat_map(|day| day.responses.keys())
+            .map(|name| name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort_unstable();
+        names.dedup();
+        if let Some(seed) = opts.seed {
+            names.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+        let ranks = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, i))
+            .collect();
+        NameOrder { ranks }
     }
 
-    let mut occ_stats = Vec::new();
-    for (person, occ) in person_occurrences {
-        cost += (occ * occ) as f32;
-        occ_stats.push((*person, occ))
+    fn rank(&self, name: &str) -> usize {
+        *self.ranks.get(name).unwrap_or(&usize::MAX)
     }
-    cost += calc_avg_distance_components(&s);
-    cost += calc_ifneedbe_components(&mut s, data);
 
-    EvaluatedSchedule::new(s, cost, occ_stats)
+    pub(crate) fn sort(&self, names: &mut [&str]) {
+        names.sort_by_key(|name| self.rank(name));
+    }
+
+    /// Whether `a` is better than `b`: it costs less or,
+    /// if the costs are equal, its assignments come first when comparing slot by slot
+    pub(crate) fn prefers(&self, a: &EvaluatedSchedule, b: &EvaluatedSchedule) -> bool {
+        if a.cost != b.cost {
+            return a.cost < b.cost;
+        }
+        self.key(a) < self.key(b)
+    }
+
+    fn key(&self, schedule: &EvaluatedSchedule) -> Vec<usize> {
+        let mut key = Vec::new();
+        for entry in &schedule.entries {
+            let start = key.len();
+            key.extend(entry.assignees.iter().map(|a| self.rank(a.name)));
+            key[start..].sort_unstable();
+        }
+        key
+    }
 }
@@ -1,25 +1,40 @@
+use framaschedule::analysis;
+use framaschedule::constraints;
+use framaschedule::constraints::{Pairing, PairingKind, ShiftLimits};
+use framaschedule::cost::CostTerm;
+use framaschedule::data::{PollColumn, Response, Slot, SlotFormat};
+use framaschedule::diagnostics;
 use framaschedule::framadate;
 use framaschedule::nextcloud;
+use framaschedule::progress::{Observer, Progress};
 use framaschedule::scheduling;
-use framaschedule::scheduling::{BestSchedules, SchedulingOptions};
+use framaschedule::scheduling::{
+    Diversity, EvaluatedSchedule, Load, ScheduleEntry, SchedulingOptions, Solver, Spacing,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[test]
 fn known_schedule_cost() {
     let data = framadate::read_data("res/test/test_poll.csv").unwrap();
-    if let BestSchedules::Two(r1, r2) =
-        scheduling::compute_all_schedules(&data, &SchedulingOptions::default())
-    {
-        assert_eq!(4.0, r1.cost);
-        assert_eq!(4.0, r2.cost);
+    let result = scheduling::compute_all_schedules(&data, &SchedulingOptions::default());
+    for r in result.schedules() {
+        assert_eq!(4.0, r.cost);
     }
 }
 
 #[test]
 fn test_nextcloud() {
     let data = nextcloud::read_data("res/test/test_nextcloud.csv").unwrap();
-    if let BestSchedules::None =
-        scheduling::compute_all_schedules(&data, &SchedulingOptions::default())
-    {
+    assert_eq!(9, data.len());
+    assert_eq!(5, data[0].responses.len());
+    assert!(matches!(data[5].responses["Dartcafe"], Response::IfNeedBe));
+    // Nobody is available for the last slot, so it needs a placeholder
+    let opts = SchedulingOptions {
+        ignore_empty_slots: true,
+        ..SchedulingOptions::default()
+    };
+    if scheduling::compute_all_schedules(&data, &opts).is_empty() {
         panic!("Expected a solution to exist")
     }
 }
@@ -27,21 +42,785 @@ fn test_nextcloud() {
 #[test]
 fn impossible_schedule() {
     let data = framadate::read_data("res/test/test_impossible.csv").unwrap();
-    match scheduling::compute_all_schedules(&data, &SchedulingOptions::default()) {
-        BestSchedules::None => (),
-        _ => panic!("Expected no solution to exist"),
+    if !scheduling::compute_all_schedules(&data, &SchedulingOptions::default()).is_empty() {
+        panic!("Expected no solution to exist")
     }
 }
 
 #[test]
 fn impossible_schedule_forced() {
     let data = framadate::read_data("res/test/test_impossible.csv").unwrap();
-    let opts = SchedulingOptions {
+    let mut opts = SchedulingOptions {
         ignore_empty_slots: true,
+        ..SchedulingOptions::default()
     };
-    if let BestSchedules::None = scheduling::compute_all_schedules(&data, &opts) {
+    if scheduling::compute_all_schedules(&data, &opts).is_empty() {
         panic!("Expected a solution to exist")
     }
+
+    // Placeholders cheaper than IfNeedBe responses still only fill slots nobody is available for
+    let data = poll(&["IfNeedBe,IfNeedBe", "Yes,No"]);
+    opts.weights.ifneedbe = 3.0;
+    opts.weights.placeholder = 1.0;
+    opts.time_limit = Some(Duration::from_secs(1));
+    let mut costs = Vec::new();
+    for solver in &[Solver::Exhaustive, Solver::Ilp, Solver::Annealing] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        let best = result.best().unwrap();
+        assert!(!best.entries.iter().any(|e| e.contains("??")));
+        costs.push(best.cost);
+    }
+    assert!((costs[0] - costs[1]).abs() < 1e-4);
+}
+
+/// A poll with one row of comma-separated responses per slot, from people named P0, P1, ...
+fn poll(rows: &[&str]) -> Vec<PollColumn> {
+    rows.iter()
+        .enumerate()
+        .map(|(slot, row)| {
+            let mut day = PollColumn::new(Slot::new(&format!("Slot {}", slot + 1)));
+            for (person, response) in row.split(',').enumerate() {
+                day.responses
+                    .insert(format!("P{}", person), response.parse().unwrap());
+            }
+            day
+        })
+        .collect()
+}
+
+#[test]
+fn multiple_people_per_slot() {
+    let mut data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    data[2].headcount = Some(3);
+    let opts = SchedulingOptions {
+        headcount: 2,
+        ..SchedulingOptions::default()
+    };
+    match scheduling::compute_all_schedules(&data, &opts).best() {
+        Some(r1) => {
+            let counts: Vec<usize> = r1.entries.iter().map(|e| e.assignees.len()).collect();
+            assert_eq!(vec![2, 2, 3, 2], counts);
+            for entry in &r1.entries {
+                let mut names: Vec<&str> = entry.assignees.iter().map(|a| a.name).collect();
+                names.sort();
+                names.dedup();
+                assert_eq!(entry.assignees.len(), names.len());
+            }
+        }
+        None => panic!("Expected a solution to exist"),
+    }
+}
+
+#[test]
+fn shift_limits() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let opts = SchedulingOptions {
+        limits: constraints::read_limits("res/test/test_limits.csv").unwrap(),
+        ..SchedulingOptions::default()
+    };
+    scheduling::check_constraints(&data, &opts).unwrap();
+    match scheduling::compute_all_schedules(&data, &opts).best() {
+        Some(r1) => {
+            let count = |person| r1.entries.iter().filter(|e| e.contains(person)).count();
+            assert!(count("Person1") <= 1);
+            assert!(count("Person4") >= 2);
+        }
+        None => panic!("Expected a solution to exist"),
+    }
+}
+
+#[test]
+fn unreachable_shift_limits() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions::default();
+    opts.limits
+        .insert("Person1".to_owned(), ShiftLimits { min: 4, max: None });
+    let err = scheduling::check_constraints(&data, &opts).unwrap_err();
+    assert!(err.as_str().contains("Person1"));
+}
+
+#[test]
+fn custom_cost_weights() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions::default();
+    opts.weights.occurrences = 2.0;
+    opts.weights.distance = 0.0;
+    match scheduling::compute_all_schedules(&data, &opts).schedules() {
+        [r1, r2] => {
+            assert_eq!(8.0, r1.cost);
+            assert_eq!(8.0, r2.cost);
+        }
+        _ => panic!("Expected two solutions to exist"),
+    }
+
+    opts.weights.distance = -5.0;
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+    opts.weights.distance = f32::NAN;
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+}
+
+/// Penalizes every shift of one person
+#[derive(Debug)]
+struct Avoid(&'static str);
+
+impl CostTerm for Avoid {
+    fn name(&self) -> &str {
+        "avoid"
+    }
+
+    fn cost(&self, schedule: &[ScheduleEntry], _: &[PollColumn], _: &SchedulingOptions) -> f32 {
+        100.0 * schedule.iter().filter(|e| e.contains(self.0)).count() as f32
+    }
+}
+
+#[test]
+fn custom_cost_term() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions::default();
+    opts.cost_terms.push(Box::new(Avoid("Person4")));
+    match scheduling::compute_all_schedules(&data, &opts).best() {
+        Some(r1) => {
+            assert!(!r1.entries.iter().any(|e| e.contains("Person4")));
+            assert_eq!(4.0, r1.cost);
+        }
+        None => panic!("Expected a solution to exist"),
+    }
+
+    // The integer program models the built-in terms
+    opts.solver = Solver::Ilp;
+    scheduling::check_constraints(&data, &opts).unwrap();
+    opts.cost_terms.remove(0);
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+}
+
+#[test]
+fn pruned_search_is_optimal() {
+    // The costs were computed by an exhaustive search without pruning
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    match scheduling::compute_all_schedules(&data, &SchedulingOptions::default()).schedules() {
+        [r1, r2] => {
+            assert!((r1.cost - 24.23942).abs() < 1e-4);
+            assert!((r2.cost - 24.25455).abs() < 1e-4);
+        }
+        _ => panic!("Expected two solutions to exist"),
+    }
+
+    // Placeholders may be cheaper than IfNeedBe responses, which the bounds must allow for
+    let data = poll(&[
+        "IfNeedBe,No,Yes",
+        "IfNeedBe,IfNeedBe,IfNeedBe",
+        "Yes,No,Yes",
+        "No,No,IfNeedBe",
+        "No,No,IfNeedBe",
+    ]);
+    let mut opts = SchedulingOptions {
+        top: 1,
+        ignore_empty_slots: true,
+        ..SchedulingOptions::default()
+    };
+    opts.weights.occurrences = 0.0;
+    opts.weights.distance = 0.0;
+    opts.weights.ifneedbe = 3.0;
+    opts.weights.placeholder = 1.0;
+    let pruned = scheduling::compute_all_schedules(&data, &opts);
+    // Nothing is pruned before this many schedules are found
+    opts.top = 1000;
+    let unpruned = scheduling::compute_all_schedules(&data, &opts);
+    assert_eq!(unpruned.best().unwrap().cost, pruned.best().unwrap().cost);
+    assert_eq!(5.0, pruned.best().unwrap().cost);
+}
+
+#[test]
+fn annealing_finds_optimum() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let opts = SchedulingOptions {
+        solver: Solver::Annealing,
+        time_limit: Some(Duration::from_millis(500)),
+        ..SchedulingOptions::default()
+    };
+    match scheduling::compute_all_schedules(&data, &opts).schedules() {
+        [r1, r2] => {
+            assert_eq!(4.0, r1.cost);
+            assert_eq!(4.0, r2.cost);
+        }
+        _ => panic!("Expected two solutions to be found"),
+    }
+}
+
+#[test]
+fn ilp_matches_exhaustive() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions {
+        headcount: 2,
+        ..SchedulingOptions::default()
+    };
+    let exhaustive = scheduling::compute_all_schedules(&data, &opts);
+    opts.solver = Solver::Ilp;
+    let ilp = scheduling::compute_all_schedules(&data, &opts);
+    match (exhaustive.schedules(), ilp.schedules()) {
+        ([e1, e2], [i1, i2]) => {
+            assert!((e1.cost - i1.cost).abs() < 1e-4);
+            assert!((e2.cost - i2.cost).abs() < 1e-4);
+        }
+        _ => panic!("Expected two solutions to exist"),
+    }
+}
+
+#[test]
+fn deterministic_results() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let assigned = |opts: &SchedulingOptions| {
+        scheduling::compute_all_schedules(&data, opts)
+            .schedules()
+            .iter()
+            .map(|r| {
+                r.entries
+                    .iter()
+                    .map(|e| e.assignees.iter().map(|a| a.name.to_owned()).collect())
+                    .collect()
+            })
+            .collect::<Vec<Vec<Vec<String>>>>()
+    };
+
+    let opts = SchedulingOptions::default();
+    assert_eq!(assigned(&opts), assigned(&opts));
+    let seeded = SchedulingOptions {
+        seed: Some(42),
+        ..SchedulingOptions::default()
+    };
+    assert_eq!(assigned(&seeded), assigned(&seeded));
+}
+
+#[test]
+fn top_schedules() {
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    let opts = SchedulingOptions {
+        top: 5,
+        ..SchedulingOptions::default()
+    };
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    let costs: Vec<f32> = result.schedules().iter().map(|r| r.cost).collect();
+    assert_eq!(5, costs.len());
+    assert!((costs[0] - 24.23942).abs() < 1e-4);
+    assert!((costs[1] - 24.25455).abs() < 1e-4);
+    assert!(costs.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    let mut opts = SchedulingOptions {
+        top: 0,
+        ..SchedulingOptions::default()
+    };
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        opts.solver = *solver;
+        assert!(scheduling::compute_all_schedules(&data, &opts).is_empty());
+    }
+}
+
+#[test]
+fn diverse_alternatives() {
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    let mut opts = SchedulingOptions {
+        top: 4,
+        diversity: Diversity::MinDifference(8),
+        threads: Some(1),
+        ..SchedulingOptions::default()
+    };
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    let schedules = result.schedules();
+    assert_eq!(4, schedules.len());
+    // The cheapest schedules that differ enough from the cheaper ones picked
+    for (r, &cost) in schedules
+        .iter()
+        .zip(&[24.23942, 24.274143, 24.480625, 24.487753])
+    {
+        assert!((r.cost - cost).abs() < 1e-4);
+    }
+    for (i, r) in schedules.iter().enumerate() {
+        assert!(schedules[..i]
+            .iter()
+            .all(|better| r.difference(better) >= 8));
+    }
+
+    opts.top = 3;
+    opts.threads = None;
+    opts.diversity = Diversity::CostTolerance(1.0);
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    let schedules = result.schedules();
+    assert_eq!(3, schedules.len());
+    assert!((schedules[0].cost - 24.23942).abs() < 1e-4);
+    assert!(schedules.iter().all(|r| r.cost <= schedules[0].cost + 1.0));
+    assert!(schedules[1..]
+        .iter()
+        .all(|r| r.difference(&schedules[0]) >= 4));
+
+    // The integer program keeps enumerating schedules until enough of them differ
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions {
+        top: 3,
+        diversity: Diversity::MinDifference(3),
+        solver: Solver::Ilp,
+        ..SchedulingOptions::default()
+    };
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    let schedules = result.schedules();
+    assert_eq!(3, schedules.len());
+    assert_eq!(4.0, schedules[0].cost);
+    for (i, r) in schedules.iter().enumerate() {
+        assert!(schedules[..i]
+            .iter()
+            .all(|better| r.difference(better) >= 3));
+    }
+    // Both solvers pick greedily from the cheapest schedules
+    let all_opts = SchedulingOptions {
+        top: 1000,
+        ..SchedulingOptions::default()
+    };
+    let all = scheduling::compute_all_schedules(&data, &all_opts);
+    assert!(all.schedules().len() < 1000);
+    let mut greedy: Vec<&EvaluatedSchedule> = Vec::new();
+    for r in all.schedules() {
+        if greedy.len() < 3 && greedy.iter().all(|picked| r.difference(picked) >= 3) {
+            greedy.push(r);
+        }
+    }
+    let costs = |schedules: &[EvaluatedSchedule]| -> Vec<f32> {
+        schedules.iter().map(|r| r.cost).collect()
+    };
+    let greedy_costs: Vec<f32> = greedy.iter().map(|r| r.cost).collect();
+    assert_eq!(greedy_costs, costs(schedules));
+    opts.solver = Solver::Exhaustive;
+    let exhaustive = scheduling::compute_all_schedules(&data, &opts);
+    assert_eq!(greedy_costs, costs(exhaustive.schedules()));
+    opts.solver = Solver::Ilp;
+    opts.diversity = Diversity::CostTolerance(1.0);
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+    // Library callers that skip the check get the exhaustive search
+    let ilp = scheduling::compute_all_schedules(&data, &opts);
+    opts.solver = Solver::Exhaustive;
+    let exhaustive = scheduling::compute_all_schedules(&data, &opts);
+    assert_eq!(exhaustive.schedules().len(), ilp.schedules().len());
+}
+
+#[test]
+fn pinned_slots() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions {
+        pins: vec!["2:Person1".parse().unwrap()],
+        ..SchedulingOptions::default()
+    };
+    scheduling::check_constraints(&data, &opts).unwrap();
+    let exhaustive = scheduling::compute_all_schedules(&data, &opts);
+    opts.solver = Solver::Ilp;
+    let ilp = scheduling::compute_all_schedules(&data, &opts);
+    for result in &[exhaustive, ilp] {
+        assert!(!result.is_empty());
+        for r in result.schedules() {
+            assert!(r.entries[1].contains("Person1"));
+        }
+    }
+
+    opts.pins = vec!["3:Person1".parse().unwrap()];
+    let err = scheduling::check_constraints(&data, &opts).unwrap_err();
+    assert!(err.as_str().contains("Person1"));
+}
+
+#[test]
+fn reschedule_after_dropout() {
+    let mut data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let result = scheduling::compute_all_schedules(&data, &SchedulingOptions::default());
+    let published = result.best().unwrap();
+    let path = std::env::temp_dir().join("framaschedule_published.csv");
+    let path = path.to_str().unwrap();
+    published.write_csv(path, &SlotFormat::Label).unwrap();
+    let dropout = published.entries[3].assignees[0].name.to_owned();
+
+    let opts = SchedulingOptions {
+        previous: constraints::read_schedule(path, &data, &SlotFormat::Label).unwrap(),
+        ..SchedulingOptions::default()
+    };
+    assert_eq!(4, opts.previous.len());
+    data[3].responses.insert(dropout.clone(), Response::No);
+    scheduling::check_constraints(&data, &opts).unwrap();
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    let r1 = result.best().unwrap();
+    assert!(!r1.entries[3].contains(&dropout));
+    let kept = opts
+        .previous
+        .iter()
+        .filter(|pin| r1.entries[pin.slot].contains(&pin.name))
+        .count();
+    assert_eq!(3, kept);
+}
+
+#[test]
+fn minimum_rest() {
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    // Without the rest, the best schedule costs 97.320274
+    let mut opts = SchedulingOptions {
+        headcount: 2,
+        min_rest: 1,
+        ..SchedulingOptions::default()
+    };
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        assert!((result.best().unwrap().cost - 99.228134).abs() < 1e-4);
+        for r in result.schedules() {
+            for pair in r.entries.windows(2) {
+                assert!(!pair[0].assignees.iter().any(|a| pair[1].contains(a.name)));
+            }
+        }
+    }
+}
+
+#[test]
+fn spacing_in_days() {
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    let start = data[1].time.start.unwrap();
+    assert_eq!(
+        "2022-01-06 18:00",
+        start.format("%Y-%m-%d %H:%M").to_string()
+    );
+
+    let opts = SchedulingOptions {
+        spacing: Spacing::Days,
+        ..SchedulingOptions::default()
+    };
+    scheduling::check_constraints(&data, &opts).unwrap();
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    assert!((result.best().unwrap().cost - 24.027445).abs() < 1e-4);
+}
+
+#[test]
+fn weighted_load() {
+    let mut data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    for (day, weight) in data.iter_mut().zip(&[1.0, 8.0, 2.5, 1.0]) {
+        day.weight = Some(*weight);
+    }
+
+    let mut opts = SchedulingOptions::default();
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        let best = result.best().unwrap();
+        // Nobody takes another shift next to the eight-hour one
+        assert!((best.cost - 72.25).abs() < 1e-4);
+        assert!(best.loads.iter().any(|&(_, load)| load == 8.0));
+        assert!(best.name_counts.iter().all(|&(_, count)| count == 1));
+    }
+
+    // Framadate polls have no end times
+    opts.load = Load::Hours;
+    data[0].weight = None;
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+}
+
+#[test]
+fn required_roles() {
+    let mut data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    data[1].roles.push("keyholder".to_owned());
+    data[1].headcount = Some(2);
+    data[3].roles.push("first-aid".to_owned());
+    let mut opts = SchedulingOptions {
+        roles: constraints::read_roles("res/test/test_roles.csv").unwrap(),
+        ..SchedulingOptions::default()
+    };
+    scheduling::check_constraints(&data, &opts).unwrap();
+
+    let mut costs = Vec::new();
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        for r in result.schedules() {
+            assert!(["Person1", "Person3"]
+                .iter()
+                .any(|name| r.entries[1].contains(name)));
+            assert!(["Person3", "Person5"]
+                .iter()
+                .any(|name| r.entries[3].contains(name)));
+        }
+        costs.push(result.best().unwrap().cost);
+    }
+    assert!((costs[0] - costs[1]).abs() < 1e-4);
+
+    // Nobody available for the third slot has a key
+    data[2].roles.push("keyholder".to_owned());
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+    opts.ignore_empty_slots = true;
+    opts.solver = Solver::Exhaustive;
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    assert!(result.best().unwrap().entries[2].contains("??"));
+}
+
+#[test]
+fn pairings() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions {
+        headcount: 2,
+        pairings: vec![
+            Pairing::parse(PairingKind::Together, "Person1,Person3").unwrap(),
+            Pairing::parse(PairingKind::Apart, "Person2,Person5").unwrap(),
+        ],
+        ..SchedulingOptions::default()
+    };
+    scheduling::check_constraints(&data, &opts).unwrap();
+
+    let mut costs = Vec::new();
+    for solver in &[Solver::Exhaustive, Solver::Ilp, Solver::Annealing] {
+        opts.solver = *solver;
+        opts.time_limit = Some(Duration::from_secs(1));
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        for r in result.schedules() {
+            for entry in &r.entries {
+                assert_eq!(entry.contains("Person1"), entry.contains("Person3"));
+                assert!(!(entry.contains("Person2") && entry.contains("Person5")));
+            }
+        }
+        costs.push(result.best().unwrap().cost);
+    }
+    assert!((costs[0] - costs[1]).abs() < 1e-4);
+
+    // Violating a soft pairing only costs extra, here for every shift of Person1 or Person2
+    opts.pairings = Vec::new();
+    opts.solver = Solver::Exhaustive;
+    let unpaired = scheduling::compute_all_schedules(&data, &opts);
+    opts.pairings = vec![Pairing::parse(PairingKind::Together, "Person1,Person2,0.5").unwrap()];
+    let exhaustive = scheduling::compute_all_schedules(&data, &opts);
+    opts.solver = Solver::Ilp;
+    let ilp = scheduling::compute_all_schedules(&data, &opts);
+    let cost = exhaustive.best().unwrap().cost;
+    assert!((cost - ilp.best().unwrap().cost).abs() < 1e-4);
+    assert!(cost > unpaired.best().unwrap().cost);
+    assert!(Pairing::parse(PairingKind::Apart, "Person2").is_err());
+    opts.pairings = vec![Pairing::parse(PairingKind::Apart, "Person1,Person2,NaN").unwrap()];
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+}
+
+#[test]
+fn history() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let last_month = scheduling::compute_all_schedules(&data, &SchedulingOptions::default());
+    let last_month = last_month.best().unwrap();
+    let path = std::env::temp_dir().join("framaschedule_history.csv");
+    let path = path.to_str().unwrap();
+    last_month.write_csv(path, &SlotFormat::Label).unwrap();
+
+    assert!(constraints::read_history(path, Load::Hours).is_err());
+    let mut history = constraints::read_history(path, Load::Shifts).unwrap();
+    for &(name, count) in &last_month.name_counts {
+        assert_eq!(count as f32, history[name]);
+    }
+    let tally = constraints::read_history("res/test/test_history.json", Load::Shifts).unwrap();
+    constraints::add_history(&mut history, tally);
+
+    let mut opts = SchedulingOptions {
+        history,
+        ..SchedulingOptions::default()
+    };
+    let mut costs = Vec::new();
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        let best = result.best().unwrap();
+        // Those who worked a lot before are spared where possible
+        assert!(!best.entries.iter().any(|entry| entry.contains("Person1")));
+        costs.push(best.cost);
+    }
+    assert!((costs[0] - costs[1]).abs() < 1e-4);
+}
+
+#[test]
+fn slot_times() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    assert_eq!("2019-02-24 Spät", data[0].time.label);
+    assert_eq!(None, data[0].time.end);
+
+    let data = nextcloud::read_data("res/test/test_nextcloud.csv").unwrap();
+    let format = "%Y-%m-%d %H:%M";
+    let slot = &data[3].time;
+    assert_eq!("Tue, Jul 13, 2021 3:00 AM", slot.label);
+    assert_eq!(
+        "2021-07-13 06:00",
+        slot.end.unwrap().format(format).to_string()
+    );
+
+    let format: SlotFormat = format.parse().unwrap();
+    assert_eq!("2021-07-13 03:00", format.format(slot));
+    assert_eq!("evening", format.format(&Slot::new("evening")));
+    assert_eq!(slot.label, SlotFormat::Label.format(slot));
+    assert!("%Y-%Q".parse::<SlotFormat>().is_err());
+}
+
+#[test]
+fn cost_breakdown() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let opts = SchedulingOptions {
+        headcount: 2,
+        pairings: vec![Pairing::parse(PairingKind::Apart, "Person1,Person2,3").unwrap()],
+        ..SchedulingOptions::default()
+    };
+    let result = scheduling::compute_all_schedules(&data, &opts);
+    for schedule in result.schedules() {
+        let terms = schedule.breakdown.iter().map(|term| term.term.as_str());
+        assert_eq!(
+            vec![
+                "occurrences",
+                "distance",
+                "ifneedbe",
+                "placeholders",
+                "changes",
+                "pairings"
+            ],
+            terms.collect::<Vec<&str>>()
+        );
+        let total: f32 = schedule.breakdown.iter().map(|term| term.total).sum();
+        assert!((schedule.cost - total).abs() < 1e-4);
+        for term in &schedule.breakdown {
+            let per_person: f32 = term.per_person.values().sum();
+            assert!((term.total - per_person).abs() < 1e-4, "{:?}", term);
+        }
+    }
+}
+
+#[test]
+fn diagnosis() {
+    let data = framadate::read_data("res/test/test_impossible.csv").unwrap();
+    let diagnosis = diagnostics::diagnose(&data, &SchedulingOptions::default());
+    let understaffed = diagnosis.understaffed.iter().map(|c| c.slots[0]);
+    assert_eq!(vec![0], understaffed.collect::<Vec<usize>>());
+    assert_eq!(None, diagnosis.conflict);
+
+    // Only Person1 is available for the first and the last slot
+    let mut data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    for &(slot, person) in &[
+        (0, "Person3"),
+        (0, "Person4"),
+        (3, "Person3"),
+        (3, "Person4"),
+        (3, "Person5"),
+    ] {
+        data[slot]
+            .responses
+            .insert(person.to_string(), Response::No);
+    }
+    // The exhaustive search assigns nobody more than one of the four shifts
+    let diagnosis = diagnostics::diagnose(&data, &SchedulingOptions::default());
+    assert_eq!(None, diagnosis.conflict);
+    assert_eq!(vec![0, 3], diagnosis.capped.unwrap().slots);
+
+    let mut opts = SchedulingOptions::default();
+    opts.limits.insert(
+        "Person1".to_owned(),
+        ShiftLimits {
+            min: 0,
+            max: Some(1),
+        },
+    );
+    let diagnosis = diagnostics::diagnose(&data, &opts);
+    assert!(diagnosis.understaffed.is_empty());
+    assert_eq!(None, diagnosis.capped);
+    let conflict = diagnosis.conflict.unwrap();
+    assert_eq!(vec![0, 3], conflict.slots);
+    assert_eq!(2, conflict.needed);
+    assert_eq!(1, conflict.available());
+}
+
+#[test]
+fn search_space() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let space = analysis::analyze(&data, &SchedulingOptions::default());
+    assert_eq!(vec![(3, 3.0), (4, 4.0), (3, 3.0), (4, 4.0)], space.slots);
+    assert_eq!(144.0, space.schedules);
+    // Nobody takes two of the four shifts
+    assert_eq!(1, space.max_occur);
+    assert!(space.leaves > 0.0 && space.leaves < space.schedules);
+    assert!(space.nodes > space.leaves);
+}
+
+#[test]
+fn cancelled_search() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    let mut opts = SchedulingOptions::default();
+    assert!(scheduling::compute_all_schedules(&data, &opts).is_optimal());
+
+    // Cancelling before the search starts leaves nothing to prove optimal
+    opts.cancel.clone().cancel();
+    for solver in &[Solver::Exhaustive, Solver::Ilp, Solver::Annealing] {
+        opts.solver = *solver;
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        assert!(!result.is_optimal());
+    }
+
+    let opts = SchedulingOptions {
+        time_limit: Some(Duration::from_secs(0)),
+        ..SchedulingOptions::default()
+    };
+    assert!(!scheduling::compute_all_schedules(&data, &opts).is_optimal());
+}
+
+#[test]
+fn thread_count() {
+    // Large enough to be split below `PARALLEL_DEPTH` and pruned using other threads' schedules
+    let data = framadate::read_data("res/test/test_large.csv").unwrap();
+    let assigned = |threads, diversity| {
+        let opts = SchedulingOptions {
+            threads: Some(threads),
+            diversity,
+            top: 3,
+            ..SchedulingOptions::default()
+        };
+        scheduling::compute_all_schedules(&data, &opts)
+            .schedules()
+            .iter()
+            .map(|r| {
+                r.entries
+                    .iter()
+                    .map(|e| e.assignees.iter().map(|a| a.name.to_owned()).collect())
+                    .collect()
+            })
+            .collect::<Vec<Vec<Vec<String>>>>()
+    };
+    for &diversity in &[Diversity::None, Diversity::MinDifference(4)] {
+        let sequential = assigned(1, diversity);
+        assert_eq!(3, sequential.len());
+        assert_eq!(sequential, assigned(2, diversity));
+        assert_eq!(sequential, assigned(8, diversity));
+    }
+    let opts = SchedulingOptions {
+        threads: Some(0),
+        ..SchedulingOptions::default()
+    };
+    assert!(scheduling::check_constraints(&data, &opts).is_err());
+}
+
+#[derive(Debug)]
+struct Recorder(Arc<Mutex<Vec<Progress>>>);
+
+impl Observer for Recorder {
+    fn progress(&self, progress: &Progress) {
+        self.0.lock().unwrap().push(progress.clone());
+    }
+}
+
+#[test]
+fn progress_reports() {
+    let data = framadate::read_data("res/test/test_poll.csv").unwrap();
+    for solver in &[Solver::Exhaustive, Solver::Ilp] {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let opts = SchedulingOptions {
+            solver: *solver,
+            observer: Some(Box::new(Recorder(reports.clone()))),
+            ..SchedulingOptions::default()
+        };
+        let result = scheduling::compute_all_schedules(&data, &opts);
+        let reports = reports.lock().unwrap();
+        let last = reports.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.branches, last.branches_done);
+        assert!(last.schedules >= result.schedules().len() as u64);
+        assert_eq!(Some(result.best().unwrap().cost), last.best_cost);
+    }
 }
 
 // TODO add tests for doodle